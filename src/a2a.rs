@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use reqwest::Client;
+use serde_json::{Value, json};
+use tracing::{info, warn};
+
+use crate::agent_tool::AgentTool;
+use crate::config::A2aAgentConfig;
+
+/// Where the card of an agent is served, relative to its URL
+pub const AGENT_CARD_PATH: &str = ".well-known/agent.json";
+
+/// A remote agent spoken to with the Agent-to-Agent protocol, JSON-RPC over HTTP
+struct RemoteAgent {
+    name: String,
+    /// The endpoint tasks are sent to, from the card of the agent
+    url: String,
+    description: String,
+    /// The names and descriptions of what the agent can do
+    skills: Vec<String>,
+}
+
+/// The remote agents of the config, shared by an agent and the agents it delegates to
+#[derive(Default)]
+pub struct A2aAgents {
+    client: Client,
+    agents: Vec<RemoteAgent>,
+}
+
+impl A2aAgents {
+    /// Fetches the cards of the agents. Agents whose card cannot be fetched are reported and skipped.
+    pub async fn connect(agents: &BTreeMap<String, A2aAgentConfig>) -> Self {
+        let client = Client::new();
+        let mut remote_agents = vec![];
+        for (name, agent) in agents {
+            match RemoteAgent::connect(&client, name, agent).await {
+                Ok(agent) => {
+                    info!("[A2A] Connected to {} at {}", name, agent.url);
+                    remote_agents.push(agent);
+                }
+                Err(e) => {
+                    warn!("Failed to fetch the card of the A2A agent {}: {}", name, e);
+                    eprintln!(
+                        "Warning: Failed to fetch the card of the A2A agent `{}`: {}",
+                        name, e
+                    );
+                }
+            }
+        }
+        Self {
+            client,
+            agents: remote_agents,
+        }
+    }
+
+    /// The remote agents, as advertised to the model
+    pub fn agent_tools(&self) -> Vec<AgentTool> {
+        self.agents
+            .iter()
+            .map(|agent| {
+                let mut description = agent.description.clone();
+                if !agent.skills.is_empty() {
+                    description.push_str(&format!("\nSkills:\n{}", agent.skills.join("\n")));
+                }
+                AgentTool::new(
+                    &format!("Remote agent {}", agent.name),
+                    &description,
+                    &format!(
+                        "{{ type: 'remote-agent', agent: '{}', task: String }}",
+                        agent.name
+                    ),
+                )
+            })
+            .collect()
+    }
+
+    /// Sends the task to the agent with `tasks/send` and waits for it to finish, returning what the agent answered
+    pub async fn send_task(&self, agent: &str, task: &str) -> Result<String, String> {
+        let agent = self
+            .agents
+            .iter()
+            .find(|known| known.name == agent)
+            .ok_or_else(|| format!("There is no remote agent `{}`.", agent))?;
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "tasks/send",
+            "params": {
+                "id": format!("{:032x}", rand::random::<u128>()),
+                "message": { "role": "user", "parts": [{ "type": "text", "text": task }] },
+            },
+        });
+        let response = self
+            .client
+            .post(&agent.url)
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?
+            .json::<Value>()
+            .await
+            .map_err(|e| e.to_string())?;
+        if let Some(error) = response.get("error") {
+            return Err(error
+                .get("message")
+                .and_then(Value::as_str)
+                .unwrap_or("unknown error")
+                .to_string());
+        }
+        let task = response.get("result").cloned().unwrap_or_default();
+        let status = task.get("status").cloned().unwrap_or_default();
+        let state = status
+            .get("state")
+            .and_then(Value::as_str)
+            .unwrap_or("unknown");
+        let mut texts = task
+            .get("artifacts")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .map(|artifact| parts_text(artifact.get("parts")))
+            .collect::<Vec<_>>();
+        if let Some(message) = status.get("message") {
+            texts.push(parts_text(message.get("parts")));
+        }
+        let text = texts
+            .into_iter()
+            .filter(|text| !text.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        match state {
+            "completed" => Ok(text),
+            state => Err(format!("the task ended as `{}`: {}", state, text)),
+        }
+    }
+}
+
+impl RemoteAgent {
+    async fn connect(
+        client: &Client,
+        name: &str,
+        agent: &A2aAgentConfig,
+    ) -> Result<Self, Box<dyn Error>> {
+        let card_url = format!("{}/{}", agent.url.trim_end_matches('/'), AGENT_CARD_PATH);
+        let card = client
+            .get(&card_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Value>()
+            .await?;
+        let skills = card
+            .get("skills")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .map(|skill| {
+                let id = skill
+                    .get("name")
+                    .or(skill.get("id"))
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                match skill.get("description").and_then(Value::as_str) {
+                    Some(description) => format!("- {}: {}", id, description),
+                    None => format!("- {}", id),
+                }
+            })
+            .collect();
+        Ok(Self {
+            name: name.to_string(),
+            url: card
+                .get("url")
+                .and_then(Value::as_str)
+                .unwrap_or(&agent.url)
+                .to_string(),
+            description: card
+                .get("description")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            skills,
+        })
+    }
+}
+
+/// The text of the parts of an A2A message or artifact, with data parts as JSON
+pub fn parts_text(parts: Option<&Value>) -> String {
+    parts
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(|part| match part.get("type").and_then(Value::as_str) {
+            Some("text") => part
+                .get("text")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            Some("data") => part.get("data").cloned().unwrap_or_default().to_string(),
+            Some(kind) => format!("[{} part]", kind),
+            None => part.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,426 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use axum::Router;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Json, Response};
+use axum::routing::{get, post};
+use chrono::Utc;
+use serde_json::{Value, json};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::info;
+
+use crate::a2a::{AGENT_CARD_PATH, parts_text};
+use crate::agent::RunOutcome;
+use crate::config::Config;
+use crate::events::EventKind;
+use crate::persona::Persona;
+use crate::persona_registry::PersonaRegistry;
+use crate::runs::{CancelError, Run, RunEvent, RunState, Runs};
+
+/// JSON-RPC error codes, the last ones are defined by A2A
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const TASK_NOT_FOUND: i64 = -32001;
+const TASK_NOT_CANCELABLE: i64 = -32002;
+
+/// Serves the personas as A2A agents over HTTP
+struct A2aServer {
+    /// Where the server is reached, e.g. `http://127.0.0.1:8100`
+    base_url: String,
+    personas: PersonaRegistry,
+    /// The tasks, run with their A2A id as the id of the run
+    runs: Arc<Runs>,
+    /// The A2A sessions the clients group their tasks in, by task, for as long as the runs keep the task
+    sessions: Mutex<HashMap<String, String>>,
+}
+
+/// Serves the Agent-to-Agent protocol on `host:port`:
+/// - `/.well-known/agent.json`: the card of fash, listing the personas as skills
+/// - `/personas/<name>/.well-known/agent.json`: the card of a persona
+/// - `/` and `/personas/<name>`: JSON-RPC with `tasks/send`, `tasks/sendSubscribe`, `tasks/resubscribe`,
+///   `tasks/get` and `tasks/cancel`, running the tasks without a persona or with the persona
+///
+/// Every task is a session of its own, which can be undone with `undo`.
+pub async fn serve(api_key: String, host: &str, port: u16) -> Result<(), Box<dyn Error>> {
+    let config = Config::load();
+    let listener = tokio::net::TcpListener::bind((host, port)).await?;
+    let base_url = format!("http://{}", listener.local_addr()?);
+    let server = Arc::new(A2aServer {
+        base_url: base_url.clone(),
+        personas: PersonaRegistry::new(config.persona_dirs())
+            .with_cache_file(config.persona_cache_file()),
+        runs: Runs::new(api_key, &config).await,
+        sessions: Mutex::new(HashMap::new()),
+    });
+    let app = Router::new()
+        .route(&format!("/{}", AGENT_CARD_PATH), get(root_card))
+        .route("/", post(root_rpc))
+        .route(
+            &format!("/personas/{{name}}/{}", AGENT_CARD_PATH),
+            get(persona_card),
+        )
+        .route("/personas/{name}", post(persona_rpc))
+        .with_state(server);
+    info!("[A2A server] Serving at {}", base_url);
+    println!("Serving the personas as A2A agents at {}", base_url);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn root_card(State(server): State<Arc<A2aServer>>) -> Json<Value> {
+    Json(server.root_card())
+}
+
+async fn persona_card(State(server): State<Arc<A2aServer>>, Path(name): Path<String>) -> Response {
+    match server.personas.get(&name) {
+        Ok(persona) => Json(server.persona_card(&name, &persona)).into_response(),
+        Err(e) => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+    }
+}
+
+async fn root_rpc(State(server): State<Arc<A2aServer>>, body: String) -> Response {
+    server.rpc(None, &body).await
+}
+
+async fn persona_rpc(
+    State(server): State<Arc<A2aServer>>,
+    Path(name): Path<String>,
+    body: String,
+) -> Response {
+    if let Err(e) = server.personas.get(&name) {
+        return (StatusCode::NOT_FOUND, e.to_string()).into_response();
+    }
+    server.rpc(Some(name), &body).await
+}
+
+impl A2aServer {
+    fn root_card(&self) -> Value {
+        let skills = self
+            .personas
+            .personas()
+            .iter()
+            .map(|(name, persona)| {
+                json!({
+                    "id": name,
+                    "name": name,
+                    "description": format!(
+                        "{} Served at {}/personas/{}",
+                        persona.description(),
+                        self.base_url,
+                        name
+                    ),
+                })
+            })
+            .collect::<Vec<_>>();
+        card(
+            "fash",
+            "An autonomous agent running tasks in a terminal. Send tasks to one of the personas listed as skills, \
+            or to this agent without a persona, optionally setting it with the `persona` of the metadata.",
+            &self.base_url,
+            skills,
+        )
+    }
+
+    fn persona_card(&self, name: &str, persona: &Persona) -> Value {
+        card(
+            persona.name(),
+            persona.description(),
+            &format!("{}/personas/{}", self.base_url, name),
+            vec![json!({
+                "id": name,
+                "name": persona.name(),
+                "description": persona.description(),
+            })],
+        )
+    }
+
+    /// Handles a JSON-RPC request sent to the agent of the persona, or of fash without a persona
+    async fn rpc(self: Arc<Self>, persona: Option<String>, body: &str) -> Response {
+        let Ok(request) = serde_json::from_str::<Value>(body) else {
+            return Json(error_response(Value::Null, PARSE_ERROR, "Parse error")).into_response();
+        };
+        let id = request.get("id").cloned().unwrap_or_default();
+        let method = request
+            .get("method")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let params = request.get("params").cloned().unwrap_or_default();
+        info!("[A2A server] {} {}", method, params);
+        let result = match method {
+            "tasks/send" => match self.start(persona, &params) {
+                Ok(task_id) => {
+                    self.runs.wait(&task_id).await;
+                    self.task(&task_id, history_length(&params))
+                }
+                Err(e) => Err(e),
+            },
+            "tasks/sendSubscribe" => match self.start(persona, &params) {
+                Ok(task_id) => match self.events(&task_id) {
+                    Some(events) => return stream(id, events),
+                    None => Err(task_not_found(&task_id)),
+                },
+                Err(e) => Err(e),
+            },
+            "tasks/resubscribe" => match task_id(&params) {
+                Ok(task_id) => match self.events(task_id) {
+                    Some(events) => return stream(id, events),
+                    None => Err(task_not_found(task_id)),
+                },
+                Err(e) => Err(e),
+            },
+            "tasks/get" => {
+                task_id(&params).and_then(|task_id| self.task(task_id, history_length(&params)))
+            }
+            "tasks/cancel" => task_id(&params).and_then(|task_id| self.cancel(task_id)),
+            _ => Err((METHOD_NOT_FOUND, format!("Method not found: {}", method))),
+        };
+        Json(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => error_response(id, code, &message),
+        })
+        .into_response()
+    }
+
+    /// Starts an agent on the task in a new session, returns the id of the task
+    fn start(&self, persona: Option<String>, params: &Value) -> Result<String, (i64, String)> {
+        let task_id = task_id(params)?.to_string();
+        let message = params
+            .get("message")
+            .ok_or((INVALID_PARAMS, "`message` is required".to_string()))?;
+        let text = parts_text(message.get("parts"));
+        if text.is_empty() {
+            return Err((INVALID_PARAMS, "The message has no text".to_string()));
+        }
+        let persona = persona.or_else(|| {
+            params
+                .pointer("/metadata/persona")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        });
+        let task_id = self
+            .runs
+            .start(Some(task_id), persona, text)
+            .map_err(|e| (INVALID_PARAMS, e))?;
+        if let Some(session_id) = params.get("sessionId").and_then(Value::as_str) {
+            let mut sessions = self.sessions.lock().unwrap();
+            // the sessions of the tasks the runs forgot are not needed anymore
+            self.runs
+                .with_runs(|runs| sessions.retain(|task_id, _| runs.contains_key(task_id)));
+            sessions.insert(task_id.clone(), session_id.to_string());
+        }
+        Ok(task_id)
+    }
+
+    /// The updates of a task as A2A events, starting with its current status if it is still running.
+    /// The events of a task that is done end with its final status.
+    fn events(&self, task_id: &str) -> Option<UnboundedReceiver<Value>> {
+        let (session, running) = self.runs.with_run(task_id, |run| {
+            (run.session().to_string(), run.state() == RunState::Running)
+        })?;
+        let mut run_events = self.runs.subscribe(task_id)?;
+        let (sender, events) = mpsc::unbounded_channel();
+        if running {
+            let _ = sender.send(status_event(task_id, RunState::Running, None, false));
+        }
+        let task_id = task_id.to_string();
+        tokio::spawn(async move {
+            while let Some(event) = run_events.recv().await {
+                for event in a2a_events(&task_id, &session, event) {
+                    if sender.send(event).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Some(events)
+    }
+
+    fn task(&self, task_id: &str, history_length: Option<usize>) -> Result<Value, (i64, String)> {
+        let session_id = self.sessions.lock().unwrap().get(task_id).cloned();
+        self.runs
+            .with_run(task_id, |run| {
+                task_json(task_id, session_id, run, history_length)
+            })
+            .ok_or_else(|| task_not_found(task_id))
+    }
+
+    fn cancel(&self, task_id: &str) -> Result<Value, (i64, String)> {
+        match self.runs.cancel(task_id) {
+            Ok(()) => self.task(task_id, None),
+            Err(CancelError::NotFound) => Err(task_not_found(task_id)),
+            Err(CancelError::Finished(state)) => Err((
+                TASK_NOT_CANCELABLE,
+                format!("Task `{}` is already {}", task_id, a2a_state(state)),
+            )),
+        }
+    }
+}
+
+/// An agent card, describing where and how to send tasks to an agent
+fn card(name: &str, description: &str, url: &str, skills: Vec<Value>) -> Value {
+    json!({
+        "name": name,
+        "description": description,
+        "url": url,
+        "provider": { "organization": "fash" },
+        "version": env!("CARGO_PKG_VERSION"),
+        "capabilities": {
+            "streaming": true,
+            "pushNotifications": false,
+            "stateTransitionHistory": false,
+        },
+        "defaultInputModes": ["text"],
+        "defaultOutputModes": ["text", "data"],
+        "skills": skills,
+    })
+}
+
+fn agent_message(text: &str) -> Value {
+    json!({ "role": "agent", "parts": [{ "type": "text", "text": text }] })
+}
+
+/// The state of a run, as named by A2A
+fn a2a_state(state: RunState) -> &'static str {
+    match state {
+        RunState::Running => "working",
+        RunState::Completed => "completed",
+        RunState::Failed => "failed",
+        RunState::Canceled => "canceled",
+    }
+}
+
+fn task_json(
+    task_id: &str,
+    session_id: Option<String>,
+    run: &Run,
+    history_length: Option<usize>,
+) -> Value {
+    let mut history =
+        vec![json!({ "role": "user", "parts": [{ "type": "text", "text": run.task() }] })];
+    history.extend(run.messages().iter().map(String::as_str).map(agent_message));
+    let start = history_length.map_or(0, |length| history.len().saturating_sub(length));
+    let (artifacts, error) = match run.finished() {
+        Some(RunEvent::Finished { outcome, error, .. }) => (
+            outcome
+                .iter()
+                .map(|outcome| outcome_artifact(run.session(), outcome))
+                .collect(),
+            error.clone(),
+        ),
+        _ => (vec![], None),
+    };
+    let message = error.or_else(|| run.messages().pop());
+    json!({
+        "id": task_id,
+        "sessionId": session_id,
+        "status": status_json(run.state(), message.as_deref(), run.updated()),
+        "artifacts": artifacts,
+        "history": history[start..],
+        "metadata": { "session": run.session() },
+    })
+}
+
+/// The result of a completed task: the messages of the agent as text and its outcome as data
+fn outcome_artifact(session: &str, outcome: &RunOutcome) -> Value {
+    let summary = format!("{}\nEnded: {}", outcome.messages.join("\n"), outcome.end);
+    json!({
+        "name": "outcome",
+        "index": 0,
+        "parts": [
+            { "type": "text", "text": summary.trim() },
+            { "type": "data", "data": { "session": session, "outcome": outcome } },
+        ],
+    })
+}
+
+fn status_json(state: RunState, message: Option<&str>, timestamp: &str) -> Value {
+    let mut status = json!({ "state": a2a_state(state), "timestamp": timestamp });
+    if let Some(message) = message {
+        status["message"] = agent_message(message);
+    }
+    status
+}
+
+fn status_event(task_id: &str, state: RunState, message: Option<&str>, last: bool) -> Value {
+    json!({
+        "id": task_id,
+        "status": status_json(state, message, &Utc::now().to_rfc3339()),
+        "final": last,
+    })
+}
+
+/// The A2A events for an event of a run
+fn a2a_events(task_id: &str, session: &str, event: RunEvent) -> Vec<Value> {
+    match event {
+        RunEvent::Agent {
+            event:
+                crate::events::Event {
+                    agent,
+                    kind: EventKind::Message { text },
+                },
+        } => {
+            let text = match agent {
+                Some(agent) => format!("[{}] {}", agent, text),
+                None => text,
+            };
+            vec![status_event(task_id, RunState::Running, Some(&text), false)]
+        }
+        RunEvent::Agent { .. } => vec![],
+        RunEvent::Finished {
+            state,
+            outcome,
+            error,
+        } => {
+            let mut events = vec![];
+            if let Some(outcome) = &outcome {
+                events
+                    .push(json!({ "id": task_id, "artifact": outcome_artifact(session, outcome) }));
+            }
+            events.push(status_event(task_id, state, error.as_deref(), true));
+            events
+        }
+    }
+}
+
+/// Streams the events as Server-Sent Events, each a JSON-RPC response to the request
+fn stream(id: Value, events: UnboundedReceiver<Value>) -> Response {
+    let events = UnboundedReceiverStream::new(events).map(move |event| {
+        Event::default().json_data(json!({ "jsonrpc": "2.0", "id": id, "result": event }))
+    });
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+fn task_id(params: &Value) -> Result<&str, (i64, String)> {
+    params
+        .get("id")
+        .and_then(Value::as_str)
+        .ok_or((INVALID_PARAMS, "`id` is required".to_string()))
+}
+
+fn history_length(params: &Value) -> Option<usize> {
+    params
+        .get("historyLength")
+        .and_then(Value::as_u64)
+        .map(|length| length as usize)
+}
+
+fn task_not_found(task_id: &str) -> (i64, String) {
+    (TASK_NOT_FOUND, format!("Task `{}` not found", task_id))
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
@@ -1,80 +1,398 @@
+use std::collections::HashMap;
 use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
-use tracing::{info, instrument};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::{Instrument, info, info_span, instrument};
 
+use crate::a2a::A2aAgents;
+use crate::agent_builder::AgentBuilder;
 use crate::agent_tool::AgentTool;
 use crate::config::Config;
+use crate::dry_run::{self, DryRun};
+use crate::events::{EventHandler, EventKind, EventLog, Events};
+use crate::file_read;
 use crate::gemini::GeminiClient;
+use crate::git::GitWorkspace;
+use crate::hooks;
+use crate::journal::Journal;
+use crate::mcp::McpServers;
+use crate::message::{self, Message, MessagePart};
+use crate::output::Output;
+use crate::permissions::Permissions;
 use crate::persona::Persona;
+use crate::persona_registry::PersonaRegistry;
+use crate::plugin_tool::PluginTool;
+use crate::search;
+use crate::shell;
 use crate::task_part::TaskPart;
+use crate::template;
 
-type Message = (&'static str, String);
+/// How deep personas can delegate tasks to other personas
+const MAX_DELEGATION_DEPTH: u32 = 3;
+
+/// The id of the next agent delegated to, unique in the process
+static NEXT_DELEGATION_ID: AtomicU64 = AtomicU64::new(1);
 
 pub struct Agent {
     config: Config,
     client: GeminiClient,
+    /// Shared with the delegated agents
+    personas: Arc<PersonaRegistry>,
     persona: Option<Persona>,
-    messages: Vec<Message>,
+    /// Restrictions set by the user of the agent, overriding the permissions of the personas
+    policy: Option<Permissions>,
+    /// The permissions of the persona with the policy applied, within the ones of the agent
+    /// that delegated to this one
+    permissions: Permissions,
+    /// The permissions of the agent that delegated to this one, `None` for the agent started by the user
+    inherited: Option<Permissions>,
+    /// The ids of the delegations from the agent started by the user to this one, see [`Journal::claim`]
+    delegations: Vec<u64>,
+    /// The directory commands are run and relative paths are resolved in, the current one if not set
+    workspace: Option<PathBuf>,
+    /// The conversation with the model, shared so that it can be read while the agent runs
+    messages: Arc<Mutex<Vec<Message>>>,
     tools: Vec<AgentTool>,
+    /// The tools of the manifests in the tool directories
+    plugin_tools: Vec<PluginTool>,
+    /// Shared with the delegated agents
+    mcp: Arc<McpServers>,
+    /// Shared with the delegated agents
+    a2a: Arc<A2aAgents>,
+    /// Whether commands can read from the terminal
+    interactive: bool,
+    /// The session the file changes are recorded in, passed to the hooks
+    session: String,
+    /// Shared with the delegated agents, which record their file changes in the same session
+    journal: Arc<Mutex<Journal>>,
+    /// The current turn of the agent, used to group file changes in the journal
+    step: u32,
+    /// Shared with the delegated agents, which commit the files they write as well
+    git: Option<Arc<Mutex<GitWorkspace>>>,
+    /// The files written by this agent in the current step, committed at its end in git mode
+    written: Vec<PathBuf>,
+    dry_run: Option<Arc<Mutex<DryRun>>>,
+    /// User supplied values for the placeholders in the instructions
+    vars: HashMap<String, String>,
+    /// How many delegations deep this agent is, 0 for the agent started by the user
+    depth: u32,
+    /// The step of the parent agent, for a delegated agent.
+    /// File changes are recorded in the journal under this step.
+    parent_step: Option<u32>,
+    /// How many steps the agent can take before it is stopped, unlimited if not set
+    max_steps: Option<u32>,
+    /// Shared with the delegated agents, whose events are labeled with their persona
+    events: Events,
+    /// Limits how many delegated tasks run at the same time, shared with the delegated agents
+    delegation_slots: Arc<Semaphore>,
+    /// The slot a delegated agent runs in, given back while it waits for its own delegated tasks
+    slot: Mutex<Option<OwnedSemaphorePermit>>,
+}
+
+/// A task delegated to a persona, run once all parts of the response are handled
+struct Delegation {
+    persona: String,
+    task: String,
+    max_steps: Option<u32>,
+}
+
+/// The result of a delegated task, as reported to the delegating agent
+#[derive(Serialize)]
+struct DelegationResult {
+    persona: String,
+    task: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outcome: Option<RunOutcome>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// The result of a task run by an agent
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunOutcome {
+    /// The messages sent to the user
+    pub messages: Vec<String>,
+    /// How the run ended
+    pub end: RunEnd,
+    pub steps: u32,
+}
+
+/// How a run ended
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum RunEnd {
+    /// The model ended the session, with the reason it gave
+    Ended { reason: String },
+    /// The agent was stopped after using up its step budget, the task may be incomplete
+    BudgetExhausted { max_steps: u32 },
+}
+
+impl std::fmt::Display for RunEnd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunEnd::Ended { reason } => write!(f, "{}", reason),
+            RunEnd::BudgetExhausted { max_steps } => write!(
+                f,
+                "Stopped after using up the budget of {} steps, the task may be incomplete",
+                max_steps
+            ),
+        }
+    }
 }
 
 impl Agent {
-    pub fn new(api_key: String) -> Self {
-        let config = Config::load();
+    pub fn new(api_key: String, session: &str) -> Self {
+        Self::create(api_key, session, None)
+    }
+
+    /// Creates an agent that runs commands and resolves relative paths in `workspace`,
+    /// using the project config of that directory
+    pub fn new_in(api_key: String, session: &str, workspace: PathBuf) -> Self {
+        let workspace = std::path::absolute(&workspace).unwrap_or(workspace);
+        Self::create(api_key, session, Some(workspace))
+    }
+
+    fn create(api_key: String, session: &str, workspace: Option<PathBuf>) -> Self {
+        let config = match &workspace {
+            Some(workspace) => Config::load_in(workspace),
+            None => Config::load(),
+        };
         let client = GeminiClient::new(api_key);
+        let journal = Journal::new(&config.journal_dir(), session);
+        let personas = Arc::new(
+            PersonaRegistry::new(config.persona_dirs())
+                .with_cache_file(config.persona_cache_file()),
+        );
+        let plugin_tools = PluginTool::load_all(&config.tool_dirs());
+        let mut events = Events::default();
+        events.subscribe(Arc::new(EventLog));
+        let delegation_slots = Arc::new(Semaphore::new(config.max_concurrent_delegations()));
 
         Self {
             config,
             client,
+            personas,
             persona: None,
-            messages: vec![],
-            tools: vec![],
+            policy: None,
+            inherited: None,
+            delegations: vec![],
+            permissions: Permissions::default(),
+            workspace,
+            messages: Arc::new(Mutex::new(vec![])),
+            tools: plugin_tools.iter().map(PluginTool::agent_tool).collect(),
+            plugin_tools,
+            mcp: Arc::new(McpServers::default()),
+            a2a: Arc::new(A2aAgents::default()),
+            interactive: true,
+            session: session.to_string(),
+            journal: Arc::new(Mutex::new(journal)),
+            step: 0,
+            git: None,
+            written: vec![],
+            dry_run: None,
+            vars: HashMap::new(),
+            depth: 0,
+            parent_step: None,
+            max_steps: None,
+            events,
+            delegation_slots,
+            slot: Mutex::new(None),
         }
     }
 
+    /// Configures an agent for a program embedding it
+    pub fn builder() -> AgentBuilder {
+        AgentBuilder::default()
+    }
+
+    /// The directory commands are run and relative paths are resolved in, if not the current one
+    pub fn workspace(&self) -> Option<&Path> {
+        self.workspace.as_deref()
+    }
+
+    /// Commits the changes of every turn to the working branch of the repository
+    pub fn set_git(&mut self, git: GitWorkspace) {
+        self.git = Some(Arc::new(Mutex::new(git)));
+    }
+
+    /// Sets the values of the `{{key}}` placeholders in the system prompt and persona instructions
+    pub fn set_vars(&mut self, vars: HashMap<String, String>) {
+        self.vars = vars;
+    }
+
+    /// Sets how the work of this agent and the agents it delegates to is shown in the terminal
+    pub fn set_output(&mut self, output: Output) {
+        self.subscribe(Arc::new(output));
+    }
+
+    /// Sends the events of this agent and the agents it delegates to to the handler
+    pub fn subscribe(&mut self, handler: Arc<dyn EventHandler>) {
+        self.events.subscribe(handler);
+    }
+
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// Makes the tools, resources and prompts of the MCP servers available to this agent and the agents it delegates to
+    pub fn set_mcp(&mut self, mcp: Arc<McpServers>) {
+        self.tools.extend(mcp.agent_tools());
+        self.mcp = mcp;
+    }
+
+    /// Makes the remote A2A agents available to this agent and the agents it delegates to
+    pub fn set_a2a(&mut self, a2a: Arc<A2aAgents>) {
+        self.tools.extend(a2a.agent_tools());
+        self.a2a = a2a;
+    }
+
+    /// Whether commands run by the agent can read from the terminal, on by default.
+    /// Turned off when stdin is used by a protocol.
+    pub fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
+
+    /// Simulates commands and file writes instead of performing them
+    pub fn set_dry_run(&mut self, dry_run: DryRun) {
+        self.dry_run = Some(Arc::new(Mutex::new(dry_run)));
+    }
+
+    /// The directory the diffs of a dry run were written to, if the agent changed any files
+    pub fn dry_run_artifact_dir(&self) -> Option<PathBuf> {
+        let dry_run = self.dry_run.as_ref()?.lock().unwrap();
+        dry_run
+            .has_changes()
+            .then(|| dry_run.artifact_dir().to_path_buf())
+    }
+
+    /// Whether the agent changed any files in this session
+    pub fn has_file_changes(&self) -> bool {
+        !self.journal.lock().unwrap().entries().is_empty()
+    }
+
     pub fn set_persona(&mut self, persona: Option<String>) -> Result<(), Box<dyn Error>> {
         if let Some(persona) = persona {
-            let persona_dir = self.config.persona_dir();
-            let persona_file_path = persona_dir.join(persona);
-            let persona = Persona::load(&persona_file_path)?;
-            if persona.allow_personas_as_tools() {
-                let persona_dir = self.config.persona_dir();
-                // get all personas
-                let persona_files = std::fs::read_dir(persona_dir)?;
-                for persona_file in persona_files {
-                    if persona_file.is_err() {
-                        continue;
-                    }
-                    let persona_file = persona_file.unwrap();
-                    let persona_file_path = persona_file.path();
-                    let persona_file_name =
-                        persona_file_path.file_stem().unwrap().to_str().unwrap();
-                    if persona_file_name.starts_with(".") {
+            let persona = self.personas.get(&persona)?;
+            let allow_personas_as_tools = persona.allow_personas_as_tools();
+            self.persona = Some(persona);
+            self.update_permissions();
+            if allow_personas_as_tools {
+                for (name, tool_persona) in self.personas.personas() {
+                    if !self.permissions.allows_persona(&name) {
                         continue;
                     }
-                    // i should cache this somehow i think
-                    let persona = Persona::load(&persona_file_path)?;
                     self.tools.push(AgentTool::new(
-                        persona.name(),
-                        persona.description(),
-                        // i should make this command dynamic -> agent-base can't always be the binary name
-                        &format!(
-                            "agent-base --persona {} --task \"<task>\"",
-                            persona_file_name
-                        ),
+                        &format!("Persona {}", tool_persona.name()),
+                        tool_persona.description(),
+                        &format!("{{ type: 'delegate', persona: '{}', task: String }}", name),
                     ));
                 }
             }
-            self.persona = Some(persona);
         }
         Ok(())
     }
 
+    /// Restricts what this agent and the agents it delegates to can do.
+    /// The fields set in the policy override the permissions of the personas.
+    /// Set it before the persona, so that personas it does not allow are not offered as tools.
+    pub fn set_policy(&mut self, policy: Permissions) {
+        self.policy = Some(policy);
+        self.update_permissions();
+    }
+
+    /// Adds a tool, in addition to the ones of the manifests in the tool directories
+    pub fn add_tool(&mut self, tool: PluginTool) {
+        self.tools.push(tool.agent_tool());
+        self.plugin_tools.push(tool);
+    }
+
+    fn update_permissions(&mut self) {
+        let permissions = self
+            .persona
+            .as_ref()
+            .map(|persona| persona.permissions().clone())
+            .unwrap_or_default();
+        let permissions = match &self.policy {
+            Some(policy) => permissions.merge(policy.clone()),
+            None => permissions,
+        };
+        self.permissions = match &self.inherited {
+            Some(inherited) => permissions.within(inherited),
+            None => permissions,
+        };
+    }
+
+    /// Sets how many steps the agent can take before it is stopped
+    pub fn set_max_steps(&mut self, max_steps: Option<u32>) {
+        self.max_steps = max_steps;
+    }
+
+    /// The conversation of the agent with the model, updated as the agent runs
+    pub fn conversation(&self) -> Arc<Mutex<Vec<Message>>> {
+        self.messages.clone()
+    }
+
+    /// The personas this agent can adopt and delegate to
+    pub fn personas(&self) -> &PersonaRegistry {
+        &self.personas
+    }
+
+    /// Runs the task until the model ends the session or the step budget is used up
     #[instrument]
-    pub async fn run(&mut self, task: &str) -> Result<(), Box<dyn Error>> {
-        let system_prompt = self.config.get_system_prompt();
+    pub async fn run(&mut self, task: &str) -> Result<RunOutcome, Box<dyn Error>> {
+        // the error is kept as text, as it is held while the hooks run and the run has to be `Send`
+        let result = self.run_steps(task).await.map_err(|e| e.to_string());
+        self.journal.lock().unwrap().release(&self.delegations);
+        match &result {
+            Ok(outcome) => self.events.emit(EventKind::End {
+                end: outcome.end.clone(),
+            }),
+            Err(e) => self.events.emit(EventKind::Error { error: e.clone() }),
+        }
+        // the hooks are only run for the agent started by the user, not for every delegated task
+        if self.depth == 0 {
+            let configured = self.config.hooks();
+            let hook_output = match &result {
+                Ok(outcome) => {
+                    self.run_hooks(
+                        hooks::ON_END,
+                        configured.on_end(),
+                        &[("FASH_END_REASON", &outcome.end.to_string())],
+                    )
+                    .await
+                }
+                Err(e) => {
+                    self.run_hooks(hooks::ON_ERROR, configured.on_error(), &[("FASH_ERROR", e)])
+                        .await
+                }
+            };
+            match hook_output {
+                Ok(output) if output.is_empty() => {}
+                Ok(output) | Err(output) => self.events.progress(output.trim_end()),
+            }
+        }
+        result.map_err(Into::into)
+    }
+
+    async fn run_steps(&mut self, task: &str) -> Result<RunOutcome, Box<dyn Error>> {
+        // the messages sent to the user
+        let mut user_messages = vec![];
+        let system_prompt = template::render(
+            &self.config.get_system_prompt(),
+            &self.vars,
+            self.workspace.as_deref(),
+        );
         let response_format = self.response_format();
-        self.messages = vec![("user", format!("The task is: {}", task))];
+        *self.messages.lock().unwrap() = vec![(
+            "user",
+            vec![MessagePart::text(format!("The task is: {}", task))],
+        )];
         let system_prompt = format!(
             "You are an instance of fash. You live at https://github.com/maheshbansod/fash-cli .
 You are an autonomous agent that will be run in a terminal with very limited user interaction.
@@ -90,13 +408,20 @@ You are an autonomous agent that will be run in a terminal with very limited use
                 format!(
                     "The persona you need to adopt is:
 {} - {}
+{}
+
 {}",
                     persona.name(),
                     persona.description(),
-                    persona.instructions()
+                    template::render(
+                        persona.instructions(),
+                        &self.vars,
+                        self.workspace.as_deref()
+                    ),
+                    self.permissions.describe().unwrap_or_default()
                 )
             } else {
-                String::new()
+                self.permissions.describe().unwrap_or_default()
             },
             if self.tools.is_empty() {
                 String::new()
@@ -106,21 +431,30 @@ You are an autonomous agent that will be run in a terminal with very limited use
                     self.tools
                         .iter()
                         .map(|tool| format!(
-                            "{}\n{}\nCommand: {}",
+                            "{}\n{}\nUsage: {}",
                             tool.name(),
                             tool.description(),
-                            tool.execution_command()
+                            tool.usage()
                         ))
                         .collect::<Vec<String>>()
                         .join("\n\n")
                 )
             }
         );
-        let mut should_exit = false;
-        while !should_exit {
+        let end = loop {
+            if let Some(max_steps) = self.max_steps
+                && self.step >= max_steps
+            {
+                info!("[Budget] Stopped after {} steps", max_steps);
+                break RunEnd::BudgetExhausted { max_steps };
+            }
+            self.step += 1;
+            self.events
+                .emit(EventKind::ModelRequest { step: self.step });
+            let messages = self.messages.lock().unwrap().clone();
             let response = self
                 .client
-                .generate_content(&self.messages, &system_prompt)
+                .generate_content(&messages, &system_prompt)
                 .await?;
             // remove the first line of response if it starts with ``` and also remove the last ``` in the response
             let response = if let Some(stripped) = response.strip_prefix("```json") {
@@ -138,65 +472,91 @@ You are an autonomous agent that will be run in a terminal with very limited use
             } else {
                 response
             };
-            // TODO: this should be debug log
-            info!("[Response] {}", response);
-            self.messages.push(("model", response.clone()));
+            self.events.emit(EventKind::ModelResponse {
+                step: self.step,
+                text: response.clone(),
+            });
+            {
+                let mut messages = self.messages.lock().unwrap();
+                // the model has seen the files attached to the last message
+                message::drop_attachments(&mut messages);
+                messages.push(("model", vec![MessagePart::text(response.clone())]));
+            }
             let response = self.parse_response(&response);
             let mut user_response = String::new();
+            let mut attachments = vec![];
+            // the first reason of the turn describes the commit in git mode
+            let mut step_reason = None;
+            // delegated tasks are run together after the other parts, so that they can run concurrently
+            let mut delegations = vec![];
+            let mut end = None;
             for part in response {
+                self.events
+                    .emit(EventKind::PartParsed { part: part.clone() });
+                if let Err(reason) = self.permissions.check(&part, self.workspace.as_deref()) {
+                    info!("[Denied] {}", reason);
+                    user_response.push_str(&format!("[denied] {}\n", reason));
+                    continue;
+                }
                 match part {
                     TaskPart::Run { command } => {
-                        use std::io::{BufRead, BufReader};
-                        use std::process::{Command, Stdio};
+                        if self.dry_run.is_some() && !dry_run::is_read_only(&command) {
+                            info!("[Dry run] Skipped command {}", command);
+                            user_response.push_str(&format!(
+                                "[dry-run] The command `{}` was not executed.",
+                                command
+                            ));
+                            continue;
+                        }
 
-                        let mut child = Command::new("sh")
-                            .arg("-c")
-                            .arg(command.clone())
-                            .stdin(Stdio::inherit())
-                            .stdout(Stdio::piped())
-                            .stderr(Stdio::piped())
-                            .spawn()?;
+                        match self
+                            .run_hooks(
+                                hooks::BEFORE_RUN_COMMAND,
+                                self.config.hooks().before_run_command(),
+                                &[("FASH_COMMAND", &command)],
+                            )
+                            .await
+                        {
+                            Ok(output) => user_response.push_str(&output),
+                            Err(e) => {
+                                info!("[Hook] Blocked command {}", command);
+                                self.events
+                                    .progress(&format!("Blocked by a hook: {}", command));
+                                user_response.push_str(&format!(
+                                    "[blocked] Running `{}` was blocked by a hook. {}\n",
+                                    command, e
+                                ));
+                                continue;
+                            }
+                        }
 
+                        self.events.emit(EventKind::CommandStarted {
+                            command: command.clone(),
+                        });
+                        let mut child = shell::command(
+                            &command,
+                            self.workspace.as_deref().unwrap_or(Path::new(".")),
+                            self.interactive,
+                        )
+                        .spawn()?;
+                        // kills what the command started if the run is cancelled while it runs
+                        let group = shell::ProcessGroup::of(&child);
                         let stdout = child.stdout.take().unwrap();
                         let stderr = child.stderr.take().unwrap();
-
-                        let output = String::new();
-                        let error = String::new();
-
-                        // Read stdout
-                        let stdout_handle = std::thread::spawn({
-                            let mut out = output.clone();
-                            move || {
-                                let reader = BufReader::new(stdout);
-                                for line in reader.lines() {
-                                    let line = line.unwrap();
-                                    println!("{}", line);
-                                    out.push_str(&line);
-                                    out.push('\n');
-                                }
-                                out
-                            }
+                        let (status, output, error) = tokio::join!(
+                            child.wait(),
+                            self.read_output(stdout, false),
+                            self.read_output(stderr, true),
+                        );
+                        let status = status?;
+                        group.release();
+                        self.events.emit(EventKind::CommandFinished {
+                            command: command.clone(),
+                            status: status.to_string(),
+                            success: status.success(),
+                            lines: output.lines().count() + error.lines().count(),
                         });
 
-                        // Read stderr
-                        let stderr_handle = std::thread::spawn({
-                            let mut err = error.clone();
-                            move || {
-                                let reader = BufReader::new(stderr);
-                                for line in reader.lines() {
-                                    let line = line.unwrap();
-                                    eprintln!("{}", line);
-                                    err.push_str(&line);
-                                    err.push('\n');
-                                }
-                                err
-                            }
-                        });
-
-                        let status = child.wait()?;
-                        let output = stdout_handle.join().unwrap();
-                        let error = stderr_handle.join().unwrap();
-
                         user_response.push_str(&format!(
                             "The output of the command `{}` is:\n```\n{}\n```",
                             command, output
@@ -211,30 +571,91 @@ You are an autonomous agent that will be run in a terminal with very limited use
                         ));
                     }
                     TaskPart::Message { text } => {
-                        info!("[Message] {}", text);
-                        println!("Bot: {}", text);
+                        self.events.emit(EventKind::Message { text: text.clone() });
+                        user_messages.push(text);
                     }
                     TaskPart::Reason { text } => {
                         info!("[Reason] {}", text);
+                        if step_reason.is_none() {
+                            step_reason = Some(text);
+                        }
                     }
-                    TaskPart::FileRead { path } => {
+                    TaskPart::FileRead {
+                        path,
+                        start,
+                        end,
+                        max_bytes,
+                    } => {
                         info!("[File read] {}", path);
-                        if let Ok(content) = std::fs::read_to_string(path.clone()) {
-                            let content = content
-                                .lines()
-                                .enumerate()
-                                .map(|(line_number, line)| format!("{}: {}", line_number + 1, line))
-                                .collect::<Vec<String>>()
-                                .join("\n");
-                            info!("[Content] {}", content);
-                            user_response.push_str(&format!(
-                                "The content of the file `{}` is:\n```\n{}\n```",
-                                path.clone(),
-                                content
-                            ));
-                        } else {
-                            user_response
-                                .push_str(&format!("The file `{}` does not exist.", path.clone()));
+                        let file = self.workspace_path(&path);
+                        if let Some(mime_type) = file_read::media_type(&path) {
+                            let attached = attachments
+                                .iter()
+                                .map(|attachment| match attachment {
+                                    MessagePart::InlineData { data, .. } => {
+                                        file_read::encoded_len(data.len())
+                                    }
+                                    MessagePart::Text(_) => 0,
+                                })
+                                .sum();
+                            match file_read::read_media(&file, attached) {
+                                Ok(data) => {
+                                    user_response.push_str(&format!(
+                                        "The file `{}` is attached ({}).",
+                                        path, mime_type
+                                    ));
+                                    attachments.push(MessagePart::InlineData { mime_type, data });
+                                }
+                                Err(e) => user_response.push_str(&e),
+                            }
+                            continue;
+                        }
+                        let start = start.map(|start| start as usize);
+                        let end = end.map(|end| end as usize);
+                        let max_bytes = max_bytes
+                            .map(|max| max as usize)
+                            .unwrap_or(file_read::DEFAULT_MAX_BYTES);
+                        let content = match self.simulated_content(&path) {
+                            Some(content) => file_read::render(
+                                &path,
+                                content.as_bytes(),
+                                content.len() as u64,
+                                start,
+                                end,
+                                max_bytes,
+                            ),
+                            None => file_read::read(&file, start, end, max_bytes),
+                        };
+                        info!("[Content] {}", content);
+                        user_response.push_str(&content);
+                    }
+                    TaskPart::Search {
+                        pattern,
+                        path,
+                        context,
+                        max_matches,
+                    } => {
+                        let path = path.unwrap_or_else(|| ".".to_string());
+                        info!("[Search] `{}` in {}", pattern, path);
+                        let max_matches = max_matches
+                            .map(|max| max as usize)
+                            .unwrap_or(search::DEFAULT_MAX_MATCHES);
+                        let context = context.unwrap_or(0) as usize;
+                        let root = self.workspace_path(&path);
+                        match search::search(&pattern, Path::new(&root), context, max_matches) {
+                            Ok(result) => {
+                                info!("[Search result] {}", result);
+                                user_response.push_str(&format!(
+                                    "The result of searching for `{}` in `{}` is:\n```\n{}\n```",
+                                    pattern, path, result
+                                ));
+                            }
+                            Err(e) => {
+                                user_response.push_str(&format!(
+                                    "The search for `{}` in `{}` failed: {}",
+                                    pattern, path, e
+                                ));
+                            }
                         }
                     }
                     TaskPart::FileWriteAdd {
@@ -244,14 +665,15 @@ You are an autonomous agent that will be run in a terminal with very limited use
                     } => {
                         info!("[FileWriteAdd] {} at {}", path, start);
                         info!("[Content] {}", content);
-                        if let Ok(file_content) = std::fs::read_to_string(path.clone()) {
+                        let hook_output = if let Ok(file_content) = self.read_file(&path) {
                             let mut lines = file_content.lines().collect::<Vec<_>>();
                             lines.insert(start as usize, &content);
                             let content = lines.join("\n");
-                            std::fs::write(path.clone(), content).unwrap();
+                            self.write_file(&path, &content).await?
                         } else {
-                            std::fs::write(path.clone(), content.clone()).unwrap();
-                        }
+                            self.write_file(&path, &content).await?
+                        };
+                        user_response.push_str(&hook_output);
                     }
                     TaskPart::FileWriteReplace {
                         path,
@@ -261,29 +683,522 @@ You are an autonomous agent that will be run in a terminal with very limited use
                     } => {
                         info!("[FileWriteReplace] {} at {} to {}", path, start, end);
                         info!("[Content] {}", content);
-                        let file_content = std::fs::read_to_string(path.clone()).unwrap();
+                        let file_content = self.read_file(&path).unwrap();
                         let mut lines = file_content.lines().collect::<Vec<_>>();
                         let start = if start == 0 { 0 } else { start as usize - 1 };
                         let end = end as usize;
                         lines.drain(start..end);
                         lines.insert(start, &content);
                         let content = lines.join("\n");
-                        std::fs::write(path.clone(), content).unwrap();
+                        let hook_output = self.write_file(&path, &content).await?;
+                        user_response.push_str(&hook_output);
+                    }
+                    TaskPart::Delegate {
+                        persona,
+                        task,
+                        max_steps,
+                    } => {
+                        info!("[Delegate] {}: {}", persona, task);
+                        delegations.push(Delegation {
+                            persona,
+                            task,
+                            max_steps,
+                        });
+                    }
+                    TaskPart::ToolCall { tool, arguments } => {
+                        info!("[Tool call] {} {}", tool, arguments);
+                        let result = self.call_tool(&tool, &arguments).await;
+                        info!("[Tool result] {}", result);
+                        user_response.push_str(&result);
+                    }
+                    TaskPart::McpResource { server, uri } => {
+                        info!("[MCP resource] {} {}", server, uri);
+                        match self.mcp.read_resource(&server, &uri).await {
+                            Ok(content) => user_response.push_str(&format!(
+                                "The content of the resource `{}` is:\n```\n{}\n```",
+                                uri, content
+                            )),
+                            Err(e) => user_response
+                                .push_str(&format!("Reading the resource `{}` failed: {}", uri, e)),
+                        }
+                    }
+                    TaskPart::McpPrompt {
+                        server,
+                        prompt,
+                        arguments,
+                    } => {
+                        info!("[MCP prompt] {} {}", server, prompt);
+                        match self.mcp.get_prompt(&server, &prompt, &arguments).await {
+                            Ok(messages) => user_response.push_str(&format!(
+                                "The prompt `{}` is:\n```\n{}\n```",
+                                prompt, messages
+                            )),
+                            Err(e) => user_response.push_str(&format!(
+                                "Getting the prompt `{}` failed: {}",
+                                prompt, e
+                            )),
+                        }
+                    }
+                    TaskPart::RemoteAgent { agent, task } => {
+                        info!("[Remote agent] {}: {}", agent, task);
+                        if self.dry_run.is_some() {
+                            info!("[Dry run] Skipped remote agent {}", agent);
+                            user_response.push_str(&format!(
+                                "[dry-run] The task was not sent to the remote agent `{}`.",
+                                agent
+                            ));
+                            continue;
+                        }
+                        self.events.progress(&format!("A2A {}: {}", agent, task));
+                        match self.a2a.send_task(&agent, &task).await {
+                            Ok(result) => {
+                                info!("[Remote agent result] {}", result);
+                                user_response.push_str(&format!(
+                                    "The result of the remote agent `{}` is:\n```\n{}\n```",
+                                    agent, result
+                                ));
+                            }
+                            Err(e) => {
+                                info!("[Remote agent] {} failed: {}", agent, e);
+                                user_response.push_str(&format!(
+                                    "The task sent to the remote agent `{}` failed: {}",
+                                    agent, e
+                                ));
+                            }
+                        }
                     }
                     TaskPart::End { reason } => {
-                        info!("[End] {}", reason);
-                        should_exit = true;
+                        end = Some(RunEnd::Ended { reason });
                     }
                 }
             }
+            if !delegations.is_empty() {
+                let results = self.delegate_all(delegations).await;
+                user_response.push_str(&format!(
+                    "The results of the delegated tasks are:\n```json\n{}\n```",
+                    serde_json::to_string_pretty(&results)?
+                ));
+            }
+            let written = std::mem::take(&mut self.written);
+            if let Some(git) = &self.git {
+                let git = git.lock().unwrap();
+                if git.commit_step(self.step, step_reason.as_deref(), &written)? {
+                    info!("[Git] Committed step {}", self.step);
+                }
+                if end.is_some() && self.depth == 0 {
+                    self.events.emit(EventKind::BranchChanges {
+                        branch: git.branch().to_string(),
+                        diff_stat: git.diff_stat()?,
+                    });
+                }
+            }
             if !user_response.is_empty() {
-                self.messages.push(("user", user_response));
+                let mut parts = vec![MessagePart::Text(user_response)];
+                parts.append(&mut attachments);
+                self.messages.lock().unwrap().push(("user", parts));
             } else {
-                self.messages.push(("user", "Please continue, use any command/tags whatever you need to. Choose the sanest option.
-                You might be missing something. Ensure you have the info about the environment that you need".to_string()));
+                self.messages.lock().unwrap().push(("user", vec![MessagePart::text("Please continue, use any command/tags whatever you need to. Choose the sanest option.
+                You might be missing something. Ensure you have the info about the environment that you need")]));
+            }
+            if let Some(end) = end {
+                break end;
             }
+        };
+        Ok(RunOutcome {
+            messages: user_messages,
+            end,
+            steps: self.step,
+        })
+    }
+
+    /// Runs the delegated tasks concurrently, each with a new agent adopting the persona, in the same process.
+    /// At most `max_concurrency` of the `[delegation]` config run at the same time.
+    /// The results are in the order the tasks were delegated in.
+    async fn delegate_all(&self, delegations: Vec<Delegation>) -> Vec<DelegationResult> {
+        // the slot is given back while waiting, so that nested delegations cannot use up all
+        // the slots and wait for each other
+        let held = self.slot.lock().unwrap().take().is_some();
+        let mut handles = vec![];
+        for delegation in delegations {
+            let agent = self
+                .delegate_agent(&delegation.persona, delegation.max_steps)
+                .map_err(|e| e.to_string());
+            let span = info_span!(
+                "delegate",
+                persona = delegation.persona,
+                depth = self.depth + 1
+            );
+            let handle = agent.map(|agent| {
+                spawn_delegation(
+                    agent,
+                    delegation.task.clone(),
+                    self.delegation_slots.clone(),
+                    span,
+                )
+            });
+            handles.push((delegation, handle));
         }
-        Ok(())
+        let mut results = vec![];
+        for (delegation, handle) in handles {
+            let result = match handle {
+                Ok(handle) => handle.await.unwrap_or_else(|e| Err(e.to_string())),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = &result {
+                info!("[Delegate] {} failed: {}", delegation.persona, e);
+            }
+            results.push(DelegationResult {
+                persona: delegation.persona,
+                task: delegation.task,
+                error: result.as_ref().err().cloned(),
+                outcome: result.ok(),
+            });
+        }
+        if held && let Ok(permit) = self.delegation_slots.clone().acquire_owned().await {
+            *self.slot.lock().unwrap() = Some(permit);
+        }
+        results
+    }
+
+    /// Creates the agent to run a task delegated to the persona
+    fn delegate_agent(
+        &self,
+        persona: &str,
+        max_steps: Option<u32>,
+    ) -> Result<Agent, Box<dyn Error>> {
+        if !self
+            .persona
+            .as_ref()
+            .is_some_and(|persona| persona.allow_personas_as_tools())
+        {
+            return Err("delegating to personas is not enabled for this persona".into());
+        }
+        if self.depth >= MAX_DELEGATION_DEPTH {
+            return Err(format!(
+                "delegated tasks cannot be nested more than {} levels deep",
+                MAX_DELEGATION_DEPTH
+            )
+            .into());
+        }
+        self.sub_agent(persona, max_steps)
+    }
+
+    /// Creates an agent adopting the persona that shares the journal and dry run of this one,
+    /// so that its file changes are part of this session.
+    /// Without `max_steps`, the default step budget of the `[delegation]` config applies.
+    pub fn sub_agent(
+        &self,
+        persona: &str,
+        max_steps: Option<u32>,
+    ) -> Result<Agent, Box<dyn Error>> {
+        self.agent_for(persona, max_steps, Some(self.permissions.clone()))
+    }
+
+    /// Creates an agent like [`Agent::sub_agent`] that works alongside this one instead of for it,
+    /// e.g. on a subtask planned by this one: it is restricted by the policy and the permissions
+    /// this agent inherited, but not by the permissions of the persona of this agent.
+    pub fn peer_agent(
+        &self,
+        persona: &str,
+        max_steps: Option<u32>,
+    ) -> Result<Agent, Box<dyn Error>> {
+        self.agent_for(persona, max_steps, self.inherited.clone())
+    }
+
+    fn agent_for(
+        &self,
+        persona: &str,
+        max_steps: Option<u32>,
+        inherited: Option<Permissions>,
+    ) -> Result<Agent, Box<dyn Error>> {
+        let config = match &self.workspace {
+            Some(workspace) => Config::load_in(workspace),
+            None => Config::load(),
+        };
+        let max_steps = max_steps.or(config.delegation_max_steps());
+        let mut agent = Agent {
+            config,
+            client: self.client.clone(),
+            personas: self.personas.clone(),
+            persona: None,
+            policy: self.policy.clone(),
+            permissions: Permissions::default(),
+            inherited,
+            delegations: [
+                self.delegations.as_slice(),
+                &[NEXT_DELEGATION_ID.fetch_add(1, Ordering::Relaxed)],
+            ]
+            .concat(),
+            workspace: self.workspace.clone(),
+            messages: Arc::new(Mutex::new(vec![])),
+            tools: self
+                .plugin_tools
+                .iter()
+                .map(PluginTool::agent_tool)
+                .chain(self.mcp.agent_tools())
+                .chain(self.a2a.agent_tools())
+                .collect(),
+            plugin_tools: self.plugin_tools.clone(),
+            mcp: self.mcp.clone(),
+            a2a: self.a2a.clone(),
+            interactive: self.interactive,
+            session: self.session.clone(),
+            journal: self.journal.clone(),
+            step: 0,
+            git: self.git.clone(),
+            written: vec![],
+            dry_run: self.dry_run.clone(),
+            vars: self.vars.clone(),
+            depth: self.depth + 1,
+            parent_step: Some(self.journal_step()),
+            max_steps,
+            events: self.events.child(persona),
+            delegation_slots: self.delegation_slots.clone(),
+            slot: Mutex::new(None),
+        };
+        agent.set_persona(Some(persona.to_string()))?;
+        Ok(agent)
+    }
+
+    /// Runs the command of the plugin tool built from the arguments, and describes the result
+    async fn call_tool(&self, name: &str, arguments: &serde_json::Value) -> String {
+        let Some(tool) = self.plugin_tools.iter().find(|tool| tool.name() == name) else {
+            if self.mcp.has_tool(name) {
+                return self.call_mcp_tool(name, arguments).await;
+            }
+            return format!("There is no tool `{}`.", name);
+        };
+        let command = match tool.command(arguments) {
+            Ok(command) => command,
+            Err(e) => return format!("The arguments for the tool `{}` are invalid: {}", name, e),
+        };
+        if let Err(reason) = self.permissions.check_command(&command) {
+            info!("[Denied] {}", reason);
+            return format!("[denied] {}\n", reason);
+        }
+        if self.dry_run.is_some() && !dry_run::is_read_only(&command) {
+            info!("[Dry run] Skipped tool {}: {}", name, command);
+            return format!("[dry-run] The tool `{}` was not run.", name);
+        }
+        let hook_output = match self
+            .run_hooks(
+                hooks::BEFORE_RUN_COMMAND,
+                self.config.hooks().before_run_command(),
+                &[("FASH_COMMAND", &command)],
+            )
+            .await
+        {
+            Ok(output) => output,
+            Err(e) => {
+                info!("[Hook] Blocked tool {}: {}", name, command);
+                self.events
+                    .progress(&format!("Blocked by a hook: {}", command));
+                return format!(
+                    "[blocked] The tool `{}` was blocked by a hook. {}\n",
+                    name, e
+                );
+            }
+        };
+        self.events.emit(EventKind::CommandStarted {
+            command: command.clone(),
+        });
+        let child = shell::command(
+            &command,
+            self.workspace.as_deref().unwrap_or(Path::new(".")),
+            false,
+        );
+        let output = match shell::output(child, tool.timeout()).await {
+            Ok(Some(output)) => output,
+            Err(e) => return format!("The tool `{}` could not be run: {}", name, e),
+            Ok(None) => {
+                return format!(
+                    "The tool `{}` was stopped after running for {} seconds.",
+                    name,
+                    tool.timeout().as_secs()
+                );
+            }
+        };
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        for (text, stderr) in [(&stdout, false), (&stderr, true)] {
+            for line in text.lines() {
+                self.events.emit(EventKind::CommandOutput {
+                    line: line.to_string(),
+                    stderr,
+                });
+            }
+        }
+        self.events.emit(EventKind::CommandFinished {
+            command,
+            status: output.status.to_string(),
+            success: output.status.success(),
+            lines: stdout.lines().count() + stderr.lines().count(),
+        });
+        if !tool.return_output() {
+            return format!(
+                "{}The tool `{}` finished with {}.",
+                hook_output, name, output.status
+            );
+        }
+        format!(
+            "{}The tool `{}` finished with {}. Its output is:\n```\n{}\n```\nIts error output is:\n```\n{}\n```",
+            hook_output, name, output.status, stdout, stderr
+        )
+    }
+
+    /// Calls the tool of an MCP server, named `server/tool`, and describes the result
+    async fn call_mcp_tool(&self, name: &str, arguments: &serde_json::Value) -> String {
+        if let Err(reason) = self.permissions.check_mcp_tool(name) {
+            info!("[Denied] {}", reason);
+            return format!("[denied] {}\n", reason);
+        }
+        if self.dry_run.is_some() {
+            info!("[Dry run] Skipped MCP tool {}", name);
+            return format!("[dry-run] The tool `{}` was not called.", name);
+        }
+        self.events.progress(&format!("MCP {}", name));
+        match self.mcp.call_tool(name, arguments).await {
+            Ok(result) => format!(
+                "The result of the tool `{}` is:\n```\n{}\n```",
+                name, result
+            ),
+            Err(e) => format!("The tool `{}` failed: {}", name, e),
+        }
+    }
+
+    /// The step file changes are recorded under in the journal
+    fn journal_step(&self) -> u32 {
+        self.parent_step.unwrap_or(self.step)
+    }
+
+    /// The path relative to the workspace, unchanged without one
+    fn workspace_path(&self, path: &str) -> String {
+        match &self.workspace {
+            Some(workspace) => workspace.join(path).display().to_string(),
+            None => path.to_string(),
+        }
+    }
+
+    /// Reads the file, as written by the agent in a dry run
+    fn read_file(&self, path: &str) -> std::io::Result<String> {
+        match self.simulated_content(path) {
+            Some(content) => Ok(content),
+            None => std::fs::read_to_string(self.workspace_path(path)),
+        }
+    }
+
+    /// Writes the file and records the change in the journal so that it can be undone,
+    /// then runs the `after_file_write` hooks and returns what they printed.
+    /// In a dry run, only the diff of the change is written to the artifact directory.
+    async fn write_file(&mut self, path: &str, content: &str) -> Result<String, Box<dyn Error>> {
+        let path = &self.workspace_path(path);
+        if let Err(reason) = self
+            .journal
+            .lock()
+            .unwrap()
+            .claim(Path::new(path), &self.delegations)
+        {
+            info!("[Denied] {}", reason);
+            return Ok(format!("[denied] {}\n", reason));
+        }
+        if let Some(dry_run) = &self.dry_run {
+            let diff_path = dry_run.lock().unwrap().write(path, content)?;
+            info!(
+                "[Dry run] Diff of {} written to {}",
+                path,
+                diff_path.display()
+            );
+            self.events.emit(EventKind::FileChanged {
+                path: path.clone(),
+                dry_run: true,
+            });
+            return Ok(String::new());
+        }
+        let before = std::fs::read(path).ok();
+        std::fs::write(path, content)?;
+        self.events.emit(EventKind::FileChanged {
+            path: path.clone(),
+            dry_run: false,
+        });
+        self.journal.lock().unwrap().record(
+            self.journal_step(),
+            Path::new(path),
+            before.as_deref(),
+            Some(content.as_bytes()),
+        )?;
+        self.written.push(PathBuf::from(path));
+        // the file is written already, so a failing hook is only reported
+        let hook_output = self
+            .run_hooks(
+                hooks::AFTER_FILE_WRITE,
+                self.config.hooks().after_file_write(),
+                &[("FASH_FILE", path)],
+            )
+            .await;
+        // a hook such as a formatter may have changed the file, which would otherwise look
+        // modified after the session to `undo`
+        let after = std::fs::read(path).ok();
+        if after.as_deref() != Some(content.as_bytes()) {
+            self.journal
+                .lock()
+                .unwrap()
+                .update_after(Path::new(path), after.as_deref())?;
+        }
+        Ok(hook_output.unwrap_or_else(|e| e))
+    }
+
+    /// Reads the output of a running command, emitting every line as it is printed
+    async fn read_output(&self, output: impl AsyncRead + Unpin, stderr: bool) -> String {
+        let mut output = BufReader::new(output);
+        let mut text = String::new();
+        let mut line = vec![];
+        // the output is read to the end even if it is not valid UTF-8, so that the command does not block
+        while output
+            .read_until(b'\n', &mut line)
+            .await
+            .is_ok_and(|read| read > 0)
+        {
+            let decoded = String::from_utf8_lossy(&line);
+            let decoded = decoded.trim_end_matches(['\n', '\r']);
+            self.events.emit(EventKind::CommandOutput {
+                line: decoded.to_string(),
+                stderr,
+            });
+            text.push_str(decoded);
+            text.push('\n');
+            line.clear();
+        }
+        text
+    }
+
+    /// Runs the hooks of the event in the workspace, see [`hooks::run`]
+    async fn run_hooks(
+        &self,
+        event: &str,
+        hooks: &[String],
+        env: &[(&str, &str)],
+    ) -> Result<String, String> {
+        if hooks.is_empty() {
+            return Ok(String::new());
+        }
+        let mut env = env.to_vec();
+        env.push(("FASH_SESSION", &self.session));
+        hooks::run(
+            event,
+            hooks,
+            self.workspace.as_deref().unwrap_or(Path::new(".")),
+            &env,
+            self.config.hooks().timeout(),
+        )
+        .await
+    }
+
+    /// The content of the file as written by the agent in a dry run, if it wrote to it
+    fn simulated_content(&self, path: &str) -> Option<String> {
+        let dry_run = self.dry_run.as_ref()?.lock().unwrap();
+        dry_run
+            .content(&self.workspace_path(path))
+            .map(str::to_string)
     }
 
     fn parse_response(&self, response: &str) -> Vec<TaskPart> {
@@ -304,7 +1219,7 @@ You are an autonomous agent that will be run in a terminal with very limited use
         ensure that the line numbers are correct and updated based on the content written in previous messages.
 
         Respond in the following format meant for fash.
-        type Message = Run | Message | Reason | FileWriteAdd | FileWriteReplace | FileRead | End;
+        type Message = Run | Message | Reason | FileWriteAdd | FileWriteReplace | FileRead | Search | ToolCall | McpResource | McpPrompt | Delegate | RemoteAgent | End;
         // Run a command
         type Run = {{
             type: 'run',
@@ -335,10 +1250,61 @@ You are an autonomous agent that will be run in a terminal with very limited use
             end: usize,
             content: String,
         }};
-        // Read a file - will return the content of the file along with line numbers
+        // Read a file - will return the content of the file along with line numbers.
+        // Large files are truncated, the response tells you which line to continue from.
+        // Images (png, jpg, webp) and PDFs are attached to the response so you can see them.
+        // Other binary files are described by their type and size instead.
         type FileRead = {{
             type: 'file-read',
             path: String,
+            start?: usize, // first line to read, defaults to 1
+            end?: usize, // last line to read (inclusive), defaults to the last line of the file
+            max_bytes?: usize, // defaults to 65536
+        }};
+        // Search file contents with a regex - prefer this over running grep.
+        // Respects .gitignore and skips binary files. Returns `path:line: text` for each match,
+        // and `path-line- text` for context lines.
+        type Search = {{
+            type: 'search',
+            pattern: String,
+            path?: String, // directory or file to search, defaults to the current directory
+            context?: usize, // number of lines to show before and after each match, defaults to 0
+            max_matches?: usize, // defaults to 100
+        }};
+        // Call a tool listed below with arguments following its schema - prefer this over running its command yourself.
+        type ToolCall = {{
+            type: 'tool-call',
+            tool: String,
+            arguments: Object,
+        }};
+        // Read a resource of an MCP server, only if its resources are listed in the tools below.
+        type McpResource = {{
+            type: 'mcp-resource',
+            server: String,
+            uri: String,
+        }};
+        // Get a prompt of an MCP server, only if its prompts are listed in the tools below.
+        type McpPrompt = {{
+            type: 'mcp-prompt',
+            server: String,
+            prompt: String,
+            arguments?: Object,
+        }};
+        // Delegate a task to another persona, only if personas are listed in the tools below.
+        // Several delegations in one response run at the same time, so split independent work between them.
+        // Returns the messages of each persona and the reason it ended with, in the order of the delegations.
+        type Delegate = {{
+            type: 'delegate',
+            persona: String,
+            task: String, // the persona only knows what you tell it here
+            max_steps?: usize, // how many steps the persona can take before it is stopped
+        }};
+        // Send a task to a remote agent, only if remote agents are listed in the tools below.
+        // Waits for the agent to finish and returns what it answered.
+        type RemoteAgent = {{
+            type: 'remote-agent',
+            agent: String,
+            task: String, // the agent only knows what you tell it here
         }};
         // End the session
         type End = {{
@@ -407,9 +1373,55 @@ You are an autonomous agent that will be run in a terminal with very limited use
     }
 }
 
+/// Runs the delegated task on its own tokio task once a slot of the semaphore is free
+fn spawn_delegation(
+    mut agent: Agent,
+    task: String,
+    semaphore: Arc<Semaphore>,
+    span: tracing::Span,
+) -> JoinHandle<Result<RunOutcome, String>> {
+    tokio::spawn(
+        async move {
+            let permit = semaphore.acquire_owned().await.map_err(|e| e.to_string())?;
+            *agent.slot.lock().unwrap() = Some(permit);
+            agent.run(&task).await.map_err(|e| e.to_string())
+        }
+        .instrument(span),
+    )
+}
+
 impl std::fmt::Debug for Agent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "<Agent>")
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[tokio::test]
+    async fn agents_working_on_planned_subtasks_can_write() {
+        let dir = temp_dir("agent_peer");
+        let mut planner = Agent::new_in("key".to_string(), "session", dir.clone());
+        planner.journal = Arc::new(Mutex::new(Journal::new(&dir.join("journal"), "session")));
+        planner.set_persona(Some("planner".to_string())).unwrap();
+        let write = TaskPart::FileWriteAdd {
+            path: "notes.txt".to_string(),
+            content: "done".to_string(),
+            start: 0,
+        };
+        assert!(planner.permissions.check(&write, Some(&dir)).is_err());
+        let delegated = planner.sub_agent("coder", None).unwrap();
+        assert!(delegated.permissions.check(&write, Some(&dir)).is_err());
+
+        let mut coder = planner.peer_agent("coder", None).unwrap();
+        coder.permissions.check(&write, Some(&dir)).unwrap();
+        coder.write_file("notes.txt", "done").await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("notes.txt")).unwrap(),
+            "done"
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::a2a::A2aAgents;
+use crate::agent::Agent;
+use crate::events::EventHandler;
+use crate::journal;
+use crate::mcp::McpServers;
+use crate::output::Output;
+use crate::permissions::Permissions;
+use crate::plugin_tool::PluginTool;
+
+/// The model provider an agent talks to
+#[derive(Clone, Debug)]
+pub enum Provider {
+    /// Google Gemini, with an API key of Google AI Studio
+    Gemini { api_key: String },
+}
+
+/// Configures an [`Agent`] for a program embedding it, see [`Agent::builder`].
+/// Unlike the agents of the CLI, it shows nothing in the terminal and its commands cannot read
+/// from it, unless [`output`](Self::output) and [`interactive`](Self::interactive) say otherwise.
+/// What the agent does can be followed with [`subscribe`](Self::subscribe).
+#[derive(Default)]
+pub struct AgentBuilder {
+    provider: Option<Provider>,
+    session: Option<String>,
+    persona: Option<String>,
+    tools: Vec<PluginTool>,
+    policy: Option<Permissions>,
+    workspace: Option<PathBuf>,
+    vars: HashMap<String, String>,
+    handlers: Vec<Arc<dyn EventHandler>>,
+    interactive: bool,
+    max_steps: Option<u32>,
+    mcp: Option<Arc<McpServers>>,
+    a2a: Option<Arc<A2aAgents>>,
+}
+
+impl AgentBuilder {
+    /// The model provider, required
+    pub fn provider(mut self, provider: Provider) -> Self {
+        self.provider = Some(provider);
+        self
+    }
+
+    /// The session the file changes are recorded in, so that they can be undone with `undo`.
+    /// A new one is created if not set.
+    pub fn session(mut self, session: impl Into<String>) -> Self {
+        self.session = Some(session.into());
+        self
+    }
+
+    /// The persona to adopt, looked up in the persona directories of the config
+    pub fn persona(mut self, persona: impl Into<String>) -> Self {
+        self.persona = Some(persona.into());
+        self
+    }
+
+    /// Adds a tool, in addition to the ones of the manifests in the tool directories
+    pub fn tool(mut self, tool: PluginTool) -> Self {
+        self.tools.push(tool);
+        self
+    }
+
+    /// Restricts what the agent and the agents it delegates to can do.
+    /// The fields set in the policy override the permissions of the personas.
+    pub fn policy(mut self, policy: Permissions) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// The directory commands are run and relative paths are resolved in, and whose project
+    /// config is used. The current directory if not set.
+    pub fn workspace(mut self, workspace: impl Into<PathBuf>) -> Self {
+        self.workspace = Some(workspace.into());
+        self
+    }
+
+    /// Sets the value of the `{{key}}` placeholder in the system prompt and persona instructions
+    pub fn var(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.vars.insert(key.into(), value.into());
+        self
+    }
+
+    /// Shows the work of the agent in the terminal, nothing is shown by default
+    pub fn output(self, output: Output) -> Self {
+        self.subscribe(Arc::new(output))
+    }
+
+    /// Sends the events of the agent and the agents it delegates to to the handler,
+    /// e.g. an `UnboundedSender<Event>`
+    pub fn subscribe(mut self, handler: Arc<dyn EventHandler>) -> Self {
+        self.handlers.push(handler);
+        self
+    }
+
+    /// Whether commands run by the agent can read from the terminal, off by default
+    pub fn interactive(mut self, interactive: bool) -> Self {
+        self.interactive = interactive;
+        self
+    }
+
+    /// How many steps the agent can take before it is stopped, unlimited by default
+    pub fn max_steps(mut self, max_steps: u32) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    /// The MCP servers whose tools, resources and prompts the agent can use
+    pub fn mcp(mut self, mcp: Arc<McpServers>) -> Self {
+        self.mcp = Some(mcp);
+        self
+    }
+
+    /// The remote A2A agents the agent can send tasks to
+    pub fn a2a(mut self, a2a: Arc<A2aAgents>) -> Self {
+        self.a2a = Some(a2a);
+        self
+    }
+
+    /// Creates the agent, failing if no provider is set or the persona cannot be loaded
+    pub fn build(self) -> Result<Agent, Box<dyn Error>> {
+        let Some(Provider::Gemini { api_key }) = self.provider else {
+            return Err("no provider is set".into());
+        };
+        let session = self.session.unwrap_or_else(journal::new_session_id);
+        let mut agent = match self.workspace {
+            Some(workspace) => Agent::new_in(api_key, &session, workspace),
+            None => Agent::new(api_key, &session),
+        };
+        for handler in self.handlers {
+            agent.subscribe(handler);
+        }
+        agent.set_interactive(self.interactive);
+        agent.set_max_steps(self.max_steps);
+        for tool in self.tools {
+            agent.add_tool(tool);
+        }
+        if let Some(mcp) = self.mcp {
+            agent.set_mcp(mcp);
+        }
+        if let Some(a2a) = self.a2a {
+            agent.set_a2a(a2a);
+        }
+        if let Some(policy) = self.policy {
+            agent.set_policy(policy);
+        }
+        agent.set_persona(self.persona)?;
+        agent.set_vars(self.vars);
+        Ok(agent)
+    }
+}
@@ -1,12 +1,17 @@
+/// A tool the agent can use, advertised to the model with how to use it
 pub struct AgentTool {
     name: String,
     description: String,
-    execution_command: String,
+    usage: String,
 }
 
 impl AgentTool {
-    pub fn new(name: &str, description: &str, execution_command: &str) -> Self {
-        Self { name: name.to_string(), description: description.to_string(), execution_command: execution_command.to_string() }
+    pub fn new(name: &str, description: &str, usage: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            usage: usage.to_string(),
+        }
     }
 
     pub fn name(&self) -> &str {
@@ -16,8 +21,8 @@ impl AgentTool {
     pub fn description(&self) -> &str {
         &self.description
     }
-    
-    pub fn execution_command(&self) -> &str {
-        &self.execution_command
+
+    pub fn usage(&self) -> &str {
+        &self.usage
     }
-}
\ No newline at end of file
+}
@@ -1,5 +1,8 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::io::{self, Write};
+use std::path::PathBuf;
+
+use agent_base::template::parse_var;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -10,19 +13,123 @@ pub struct Args {
     /// The persona to use for the task
     #[arg(short, long)]
     pub persona: Option<String>,
+    /// In a git repository, work on a new branch and commit the changes of every step
+    #[arg(long, conflicts_with = "dry_run")]
+    pub git: bool,
+    /// Simulate the task: only read-only commands are run and file changes are written as diffs
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Where the diffs of a dry run are written, defaults to `fash_<session>_dry_run`
+    #[arg(long, requires = "dry_run")]
+    pub artifact_dir: Option<PathBuf>,
+    /// A value for a `{{key}}` placeholder in the instructions, as `key=value`. Can be repeated.
+    #[arg(long = "var", value_parser = parse_var)]
+    pub vars: Vec<(String, String)>,
+    /// Only show the messages of the agents and the final results
+    #[arg(short, long)]
+    pub quiet: bool,
+    /// Show the full output of the commands run by the agents instead of a summary
+    #[arg(long, conflicts_with = "quiet")]
+    pub show_output: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Revert the file changes made by the agent in a session
+    Undo {
+        /// The session to revert, lists the sessions with changes if not given
+        session: Option<String>,
+        /// Only revert the changes made in this step and the steps after it
+        #[arg(short, long)]
+        step: Option<u32>,
+        /// Revert files even if they were modified after the session
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Let a planner split the task into subtasks for other personas, run them and summarise the results
+    Orchestrate {
+        /// The task to orchestrate
+        #[arg(short, long)]
+        task: Option<String>,
+        /// The persona that plans the subtasks and summarises their results
+        #[arg(long, default_value = "planner")]
+        planner: String,
+    },
+    /// Serve the personas over the Model Context Protocol on stdio, as `run_task` and `list_personas` tools
+    McpServe,
+    /// Serve a REST API to start, follow and cancel runs of tasks
+    Serve {
+        /// The address to listen on, only reachable from this machine by default
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+    },
+    /// Serve the personas as agents over the Agent-to-Agent protocol on HTTP
+    A2aServe {
+        /// The address to listen on, only reachable from this machine by default
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+        #[arg(long, default_value_t = 8100)]
+        port: u16,
+    },
+    /// Manage the personas
+    Persona {
+        #[command(subcommand)]
+        command: PersonaCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum PersonaCommand {
+    /// List the personas
+    List,
+    /// Print the file of a persona
+    Show { name: String },
+    /// Create a persona from a template
+    New {
+        name: String,
+        /// Create the persona in the `.fash/personas` directory of the project instead of the global one
+        #[arg(long)]
+        project: bool,
+    },
+    /// Open a persona in $VISUAL or $EDITOR and validate it afterwards
+    Edit { name: String },
+    /// Check that personas have all the required fields and no unknown ones, all of them if no name is given
+    Validate { name: Option<String> },
+    /// Copy the built-in personas to the persona directory to customise them
+    InstallDefaults {
+        /// Overwrite personas that already exist
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Remove a persona
+    Rm {
+        name: String,
+        /// Do not ask for confirmation
+        #[arg(short, long)]
+        force: bool,
+    },
 }
 
 impl Args {
     pub fn get_task(&self) -> io::Result<String> {
-        Ok(match &self.task {
-            Some(task) => task.clone(),
-            None => {
-                print!("Enter your task: ");
-                io::stdout().flush()?;
-                let mut input = String::new();
-                io::stdin().read_line(&mut input)?;
-                input.trim().to_string()
-            }
-        })
+        task_or_prompt(self.task.clone())
     }
 }
+
+/// The given task, or the one entered by the user if none is given
+pub fn task_or_prompt(task: Option<String>) -> io::Result<String> {
+    Ok(match task {
+        Some(task) => task,
+        None => {
+            print!("Enter your task: ");
+            io::stdout().flush()?;
+            let mut input = String::new();
+            io::stdin().read_line(&mut input)?;
+            input.trim().to_string()
+        }
+    })
+}
@@ -1,19 +1,81 @@
 use directories::ProjectDirs;
 use serde::Deserialize;
-use std::{fs, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 pub const ORG_NAME: &str = "light";
 pub const APP_NAME: &str = "Fash CLI";
+/// Name of the directory with the project-local config and personas
+pub const PROJECT_DIR_NAME: &str = ".fash";
+const DEFAULT_HOOK_TIMEOUT_SECS: u64 = 60;
 
 #[derive(Deserialize, Default, Debug)]
 struct ConfigRaw {
     system_prompt: Option<SystemPrompt>,
+    delegation: Option<DelegationConfig>,
+    mcp_servers: Option<BTreeMap<String, McpServerConfig>>,
+    a2a_agents: Option<BTreeMap<String, A2aAgentConfig>>,
+    hooks: Option<HooksConfig>,
+}
+
+impl ConfigRaw {
+    fn read(config_path: &Path) -> Self {
+        if !config_path.exists() {
+            return ConfigRaw::default();
+        }
+        match fs::read_to_string(config_path) {
+            Ok(content) => toml::from_str(&content).unwrap_or_else(|e| {
+                eprintln!(
+                    "Warning: Failed to parse config file {}: {}",
+                    config_path.display(),
+                    e
+                );
+                ConfigRaw::default()
+            }),
+            Err(e) => {
+                eprintln!(
+                    "Warning: Failed to read config file {}: {}",
+                    config_path.display(),
+                    e
+                );
+                ConfigRaw::default()
+            }
+        }
+    }
+
+    /// Overrides the fields of `self` with the ones set in `other`
+    fn merge(self, other: ConfigRaw) -> Self {
+        ConfigRaw {
+            system_prompt: other.system_prompt.or(self.system_prompt),
+            delegation: match (self.delegation, other.delegation) {
+                (Some(base), Some(delegation)) => Some(DelegationConfig {
+                    max_concurrency: delegation.max_concurrency.or(base.max_concurrency),
+                    max_steps: delegation.max_steps.or(base.max_steps),
+                }),
+                (base, delegation) => delegation.or(base),
+            },
+            // MCP servers, A2A agents and hooks are only read from the global config, see `Config::load_in`
+            mcp_servers: self.mcp_servers,
+            a2a_agents: self.a2a_agents,
+            hooks: self.hooks,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Config {
     pub system_prompt: Option<SystemPrompt>,
+    delegation: DelegationConfig,
+    mcp_servers: BTreeMap<String, McpServerConfig>,
+    a2a_agents: BTreeMap<String, A2aAgentConfig>,
+    hooks: HooksConfig,
     proj_dirs: ProjectDirs,
+    /// The `.fash` directory of the project the current directory is in, if any
+    project_dir: Option<PathBuf>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -22,8 +84,85 @@ pub struct SystemPrompt {
     pub inline_text: Option<String>,
 }
 
+/// A Model Context Protocol server, run as a child process and spoken to over its stdio
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct McpServerConfig {
+    pub command: String,
+    pub args: Option<Vec<String>>,
+    pub env: Option<BTreeMap<String, String>>,
+    /// How many seconds to wait for the response to a request, 60 by default
+    pub timeout: Option<u64>,
+}
+
+/// A remote agent spoken to with the Agent-to-Agent protocol
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct A2aAgentConfig {
+    /// Where the agent is served, its card is at `<url>/.well-known/agent.json`
+    pub url: String,
+}
+
+/// Shell commands run on events of the agents, in order, see [`crate::hooks`]
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HooksConfig {
+    /// Run before every command of the agent or a tool, a non-zero exit status blocks the command
+    #[serde(default)]
+    before_run_command: Vec<String>,
+    /// Run after every file written by the agent
+    #[serde(default)]
+    after_file_write: Vec<String>,
+    /// Run when the agent started by the user ends the session or uses up its budget
+    #[serde(default)]
+    on_end: Vec<String>,
+    /// Run when the agent started by the user fails
+    #[serde(default)]
+    on_error: Vec<String>,
+    /// How many seconds a hook can run before it is stopped, 60 by default
+    timeout: Option<u64>,
+}
+
+impl HooksConfig {
+    pub fn before_run_command(&self) -> &[String] {
+        &self.before_run_command
+    }
+
+    pub fn after_file_write(&self) -> &[String] {
+        &self.after_file_write
+    }
+
+    pub fn on_end(&self) -> &[String] {
+        &self.on_end
+    }
+
+    pub fn on_error(&self) -> &[String] {
+        &self.on_error
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT_SECS))
+    }
+}
+
+/// How tasks delegated to other personas are run
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct DelegationConfig {
+    /// How many delegated tasks can run at the same time
+    max_concurrency: Option<usize>,
+    /// How many steps a delegated task can take, unless the delegating agent sets it
+    max_steps: Option<u32>,
+}
+
 impl Config {
+    /// Loads the global config and the project config of the current directory
     pub fn load() -> Self {
+        Self::load_in(&std::env::current_dir().unwrap_or_default())
+    }
+
+    /// Loads the global config and the project config of `dir`
+    pub fn load_in(dir: &Path) -> Self {
         let proj_dirs =
             directories::ProjectDirs::from("com", ORG_NAME, APP_NAME).unwrap_or_else(|| {
                 eprintln!("Warning: Could not determine config directory, using defaults");
@@ -33,40 +172,133 @@ impl Config {
 
         let config_dir = proj_dirs.config_dir();
         let config_path = config_dir.join("config.toml");
+        let mut config_raw = ConfigRaw::read(&config_path);
 
-        let config_raw = {
-            if !config_path.exists() {
-                ConfigRaw::default()
-            } else {
-                match fs::read_to_string(&config_path) {
-                    Ok(content) => toml::from_str(&content).unwrap_or_else(|e| {
-                        eprintln!("Warning: Failed to parse config file: {}", e);
-                        ConfigRaw::default()
-                    }),
-                    Err(e) => {
-                        eprintln!("Warning: Failed to read config file: {}", e);
-                        ConfigRaw::default()
-                    }
-                }
+        let home = directories::BaseDirs::new().map(|dirs| dirs.home_dir().to_path_buf());
+        let project_dir = Self::find_project_dir(dir, home.as_deref());
+        if let Some(project_dir) = &project_dir {
+            let mut project_config = ConfigRaw::read(&project_dir.join("config.toml"));
+            // relative paths in the project config are relative to the project root
+            if let Some(system_prompt) = &mut project_config.system_prompt
+                && let Some(file_path) = &mut system_prompt.file_path
+                && let Some(project_root) = project_dir.parent()
+            {
+                *file_path = project_root.join(&*file_path).display().to_string();
+            }
+            // the commands of a cloned repository are not run, and the work is not sent to the
+            // remote agents it declares, without the user asking for it
+            let ignored = [
+                ("mcp_servers", project_config.mcp_servers.take().is_some()),
+                ("a2a_agents", project_config.a2a_agents.take().is_some()),
+                ("hooks", project_config.hooks.take().is_some()),
+            ]
+            .into_iter()
+            .filter_map(|(table, set)| set.then_some(table))
+            .collect::<Vec<_>>();
+            if !ignored.is_empty() {
+                eprintln!(
+                    "Warning: Ignoring {} in {}, they can only be declared in the global config {}",
+                    ignored.join(", "),
+                    project_dir.join("config.toml").display(),
+                    config_path.display()
+                );
             }
-        };
+            config_raw = config_raw.merge(project_config);
+        }
+
         Config {
             system_prompt: config_raw.system_prompt,
+            delegation: config_raw.delegation.unwrap_or_default(),
+            mcp_servers: config_raw.mcp_servers.unwrap_or_default(),
+            a2a_agents: config_raw.a2a_agents.unwrap_or_default(),
+            hooks: config_raw.hooks.unwrap_or_default(),
             proj_dirs,
+            project_dir,
+        }
+    }
+
+    /// Finds the `.fash` directory in `dir` or the closest of its parents, up to but not including
+    /// the home directory, whose `.fash` directory is not a project
+    fn find_project_dir(dir: &Path, home: Option<&Path>) -> Option<PathBuf> {
+        dir.ancestors()
+            .take_while(|dir| Some(*dir) != home)
+            .map(|dir| dir.join(PROJECT_DIR_NAME))
+            .find(|dir| dir.is_dir())
+    }
+
+    pub fn project_dir(&self) -> Option<&Path> {
+        self.project_dir.as_deref()
+    }
+
+    /// The directories personas are looked up in, in order: the project personas, then the global ones
+    pub fn persona_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = vec![];
+        if let Some(project_dir) = &self.project_dir {
+            dirs.push(project_dir.join("personas"));
+        }
+        dirs.push(self.persona_dir());
+        dirs
+    }
+
+    /// The directories tool manifests are looked up in, in order: the project tools, then the global ones
+    pub fn tool_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = vec![];
+        if let Some(project_dir) = &self.project_dir {
+            dirs.push(project_dir.join("tools"));
         }
+        dirs.push(self.proj_dirs.data_dir().join("tools"));
+        dirs
     }
 
     pub fn persona_dir(&self) -> PathBuf {
         self.proj_dirs.data_dir().join("personas")
     }
 
+    /// Where the parsed personas are cached between runs
+    pub fn persona_cache_file(&self) -> PathBuf {
+        self.proj_dirs.cache_dir().join("personas.json")
+    }
+
+    /// How many delegated tasks can run at the same time, 4 by default
+    pub fn max_concurrent_delegations(&self) -> usize {
+        self.delegation.max_concurrency.unwrap_or(4).max(1)
+    }
+
+    /// How many steps a delegated task can take by default, unlimited if not set
+    pub fn delegation_max_steps(&self) -> Option<u32> {
+        self.delegation.max_steps
+    }
+
+    /// The MCP servers by name
+    pub fn mcp_servers(&self) -> &BTreeMap<String, McpServerConfig> {
+        &self.mcp_servers
+    }
+
+    /// The remote A2A agents by name
+    pub fn a2a_agents(&self) -> &BTreeMap<String, A2aAgentConfig> {
+        &self.a2a_agents
+    }
+
+    pub fn hooks(&self) -> &HooksConfig {
+        &self.hooks
+    }
+
+    pub fn journal_dir(&self) -> PathBuf {
+        self.proj_dirs.data_dir().join("journal")
+    }
+
+    /// Where the logs of the sessions are written, outside of the projects worked on
+    pub fn log_dir(&self) -> PathBuf {
+        self.proj_dirs.data_dir().join("logs")
+    }
+
     pub fn get_system_prompt(&self) -> String {
         if let Some(system_prompt) = &self.system_prompt {
             // Try file path first
-            if let Some(file_path) = &system_prompt.file_path {
-                if let Ok(content) = fs::read_to_string(file_path) {
-                    return content;
-                }
+            if let Some(file_path) = &system_prompt.file_path
+                && let Ok(content) = fs::read_to_string(file_path)
+            {
+                return content;
             }
             // Try inline text
             if let Some(text) = &system_prompt.inline_text {
@@ -77,3 +309,30 @@ impl Config {
         "You are a helpful AI assistant.".to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn finds_the_project_dir_below_the_home_dir() {
+        let home = temp_dir("config");
+        let project = home.join("project");
+        let nested = project.join("src/nested");
+        fs::create_dir_all(&nested).unwrap();
+        fs::create_dir_all(home.join(PROJECT_DIR_NAME)).unwrap();
+
+        assert_eq!(Config::find_project_dir(&nested, Some(&home)), None);
+        fs::create_dir_all(project.join(PROJECT_DIR_NAME)).unwrap();
+        assert_eq!(
+            Config::find_project_dir(&nested, Some(&home)),
+            Some(project.join(PROJECT_DIR_NAME))
+        );
+        assert_eq!(Config::find_project_dir(&home, Some(&home)), None);
+        assert_eq!(
+            Config::find_project_dir(&home, None),
+            Some(home.join(PROJECT_DIR_NAME))
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use similar::TextDiff;
+
+/// Commands that only read and are run even in a dry run
+const READ_ONLY_COMMANDS: &[&str] = &[
+    "cat", "head", "tail", "ls", "pwd", "wc", "grep", "rg", "find", "tree", "file", "stat", "echo",
+    "which", "whoami", "uname", "date", "diff",
+];
+
+/// Git subcommands that only read
+const READ_ONLY_GIT_COMMANDS: &[&str] = &["status", "log", "diff", "show", "ls-files"];
+
+/// Arguments that make an otherwise read-only command modify things or run other commands
+const UNSAFE_ARGS: &[&str] = &["-exec", "-execdir", "-delete", "-ok", "-okdir"];
+
+/// Simulates the side effects of the agent.
+/// File writes are kept in memory, so that the agent reads back what it wrote,
+/// and the diff of every changed file against its original content is written to the artifact directory.
+pub struct DryRun {
+    artifact_dir: PathBuf,
+    /// The original and the simulated content of the files written by the agent
+    files: HashMap<PathBuf, (Option<String>, String)>,
+}
+
+impl DryRun {
+    pub fn new(artifact_dir: PathBuf) -> Self {
+        Self {
+            artifact_dir,
+            files: HashMap::new(),
+        }
+    }
+
+    pub fn artifact_dir(&self) -> &Path {
+        &self.artifact_dir
+    }
+
+    /// Whether the agent has written to any file
+    pub fn has_changes(&self) -> bool {
+        !self.files.is_empty()
+    }
+
+    /// The simulated content of the file, if the agent has written to it
+    pub fn content(&self, path: &str) -> Option<&str> {
+        let path = std::path::absolute(path).ok()?;
+        self.files.get(&path).map(|(_, content)| content.as_str())
+    }
+
+    /// Simulates writing `content` to the file at `path` and updates its diff in the artifact directory.
+    /// Returns the path of the diff.
+    pub fn write(&mut self, path: &str, content: &str) -> Result<PathBuf, Box<dyn Error>> {
+        let absolute = std::path::absolute(path)?;
+        let original = match self.files.remove(&absolute) {
+            Some((original, _)) => original,
+            None => fs::read_to_string(&absolute).ok(),
+        };
+        let diff = TextDiff::from_lines(original.as_deref().unwrap_or(""), content)
+            .unified_diff()
+            .header(
+                &if original.is_some() {
+                    format!("a/{}", path)
+                } else {
+                    "/dev/null".to_string()
+                },
+                &format!("b/{}", path),
+            )
+            .to_string();
+        fs::create_dir_all(&self.artifact_dir)?;
+        let diff_name = format!(
+            "{}.diff",
+            path.trim_start_matches("./").replace(['/', '\\'], "__")
+        );
+        let diff_path = self.artifact_dir.join(diff_name);
+        fs::write(&diff_path, diff)?;
+        self.files.insert(absolute, (original, content.to_string()));
+        Ok(diff_path)
+    }
+}
+
+/// Whether the command only reads and can safely be run in a dry run.
+/// Every command in a pipeline or list has to be read-only, and output redirection,
+/// command and process substitution are not allowed.
+pub fn is_read_only(command: &str) -> bool {
+    if command.contains('>')
+        || command.contains('`')
+        || command.contains("$(")
+        || command.contains("<(")
+    {
+        return false;
+    }
+    command
+        .split(['|', ';', '&', '\n'])
+        .map(str::trim)
+        .filter(|segment| !segment.is_empty())
+        .all(|segment| {
+            // quotes and escapes are removed by the shell, so they cannot hide an argument
+            let words = segment
+                .split_whitespace()
+                .map(|word| word.replace(['\'', '"', '\\'], ""))
+                .collect::<Vec<_>>();
+            let words = words.iter().map(String::as_str).collect::<Vec<_>>();
+            match words.as_slice() {
+                ["git", subcommand, args @ ..] => {
+                    READ_ONLY_GIT_COMMANDS.contains(subcommand)
+                        && !args.iter().any(|arg| is_unsafe_arg("git", arg))
+                }
+                [program, args @ ..] => {
+                    READ_ONLY_COMMANDS.contains(program)
+                        && !args.iter().any(|arg| is_unsafe_arg(program, arg))
+                }
+                [] => true,
+            }
+        })
+}
+
+/// Whether the argument makes the read-only program write files or run other commands
+fn is_unsafe_arg(program: &str, arg: &str) -> bool {
+    if UNSAFE_ARGS.contains(&arg) {
+        return true;
+    }
+    let option = arg.split('=').next().unwrap_or(arg);
+    match program {
+        // -fprint, -fprint0, -fprintf and -fls write to a file
+        "find" => option.starts_with("-fprint") || option == "-fls",
+        // git accepts unambiguous prefixes of long options, e.g. `--outp=file`
+        "git" => option
+            .strip_prefix("--")
+            .is_some_and(|name| name.len() >= 3 && "output".starts_with(name)),
+        // the preprocessor is run on every searched file
+        "rg" => option == "--pre" || option == "--pre-glob",
+        "tree" => option == "-o",
+        "date" => option == "-s" || option == "--set",
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_read_only_commands() {
+        assert!(is_read_only("ls -la"));
+        assert!(is_read_only("cat src/main.rs | grep fn | wc -l"));
+        assert!(is_read_only("git log --oneline -5 && git status"));
+        assert!(is_read_only("find . -name '*.rs' -print"));
+        assert!(is_read_only("rg --pretty fn src"));
+    }
+
+    #[test]
+    fn rejects_other_commands() {
+        assert!(!is_read_only("rm -rf target"));
+        assert!(!is_read_only("ls && touch file"));
+        assert!(!is_read_only("git commit -m wip"));
+        assert!(!is_read_only("git -c core.pager=sh status"));
+    }
+
+    #[test]
+    fn rejects_redirection_and_substitution() {
+        assert!(!is_read_only("echo hi > file"));
+        assert!(!is_read_only("echo `touch file`"));
+        assert!(!is_read_only("echo $(touch file)"));
+        assert!(!is_read_only("diff <(touch file) a"));
+        assert!(!is_read_only("cat >(tee file)"));
+    }
+
+    #[test]
+    fn rejects_find_actions() {
+        for action in [
+            "-exec rm {} ;",
+            "-execdir rm {} +",
+            "-delete",
+            "-ok rm {} ;",
+        ] {
+            assert!(!is_read_only(&format!("find . {}", action)), "{}", action);
+        }
+        for action in ["-fprint", "-fprint0", "-fprintf", "-fls"] {
+            assert!(
+                !is_read_only(&format!("find . {} out", action)),
+                "{}",
+                action
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_git_output() {
+        assert!(!is_read_only("git diff --output=out.txt"));
+        assert!(!is_read_only("git log --output out.txt"));
+        assert!(!is_read_only("git show --outp=out.txt"));
+        assert!(!is_read_only("git diff '--output=out.txt'"));
+    }
+
+    #[test]
+    fn rejects_rg_preprocessors() {
+        assert!(!is_read_only("rg --pre ./script.sh pattern"));
+        assert!(!is_read_only("rg --pre=./script.sh pattern"));
+        assert!(!is_read_only("rg --pre-glob '*' pattern"));
+        assert!(!is_read_only("rg \"--pre\" sh pattern"));
+    }
+
+    #[test]
+    fn rejects_other_writing_options() {
+        assert!(!is_read_only("tree -o out.txt"));
+        assert!(!is_read_only("date -s 2020-01-01"));
+    }
+}
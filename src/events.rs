@@ -0,0 +1,264 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::info;
+
+use crate::agent::RunEnd;
+use crate::task_part::TaskPart;
+
+/// Something an agent did, sent to the subscribers of the agent as it happens
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    /// The personas delegated to, from the agent started by the user, e.g. `coder/reviewer`.
+    /// `None` for the agent started by the user.
+    pub agent: Option<String>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// What an agent did
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum EventKind {
+    /// The conversation is sent to the model, at the start of every step
+    ModelRequest {
+        step: u32,
+    },
+    /// The model answered
+    ModelResponse {
+        step: u32,
+        text: String,
+    },
+    /// A part of the response of the model, before it is checked and executed
+    PartParsed {
+        part: TaskPart,
+    },
+    CommandStarted {
+        command: String,
+    },
+    /// A line printed by a running command
+    CommandOutput {
+        line: String,
+        stderr: bool,
+    },
+    CommandFinished {
+        command: String,
+        /// The exit status, e.g. `exit status: 1`
+        status: String,
+        success: bool,
+        /// How many lines the command printed
+        lines: usize,
+    },
+    /// A file was written, or its diff in a dry run
+    FileChanged {
+        path: String,
+        dry_run: bool,
+    },
+    /// A message to the user
+    Message {
+        text: String,
+    },
+    /// What the agents are doing besides running commands, e.g. calling an MCP tool
+    Progress {
+        text: String,
+    },
+    /// The changes made on the git branch of the session, when the run is over
+    BranchChanges {
+        branch: String,
+        /// The files changed and how many lines, as shown by `git diff --stat`
+        diff_stat: String,
+    },
+    /// The run is over
+    End {
+        end: RunEnd,
+    },
+    /// The run failed
+    Error {
+        error: String,
+    },
+}
+
+/// Receives the events of agents, e.g. to show them or to send them to a client.
+/// It is called on the thread of the agent, so it should not block.
+pub trait EventHandler: Send + Sync {
+    fn handle(&self, event: &Event);
+}
+
+/// Sends the events to a channel, for as long as the receiver is there
+impl EventHandler for UnboundedSender<Event> {
+    fn handle(&self, event: &Event) {
+        let _ = self.send(event.clone());
+    }
+}
+
+/// Writes the events to the log
+pub struct EventLog;
+
+impl EventHandler for EventLog {
+    fn handle(&self, event: &Event) {
+        match &event.kind {
+            EventKind::ModelRequest { step } => info!("[Request] step {}", step),
+            EventKind::ModelResponse { text, .. } => info!("[Response] {}", text),
+            EventKind::PartParsed { part } => info!("[Part] {:?}", part),
+            EventKind::CommandStarted { command } => info!("[Command] {}", command),
+            // the output is logged with the result of the command
+            EventKind::CommandOutput { .. } => {}
+            EventKind::CommandFinished {
+                command, status, ..
+            } => info!("[Command] {} finished with {}", command, status),
+            EventKind::FileChanged { path, dry_run } => {
+                info!(
+                    "[File changed] {}{}",
+                    path,
+                    if *dry_run { " (dry run)" } else { "" }
+                )
+            }
+            EventKind::Message { text } => info!("[Message] {}", text),
+            EventKind::Progress { text } => info!("[Progress] {}", text),
+            EventKind::BranchChanges { branch, diff_stat } => {
+                info!("[Branch changes] {}\n{}", branch, diff_stat)
+            }
+            EventKind::End { end } => info!("[End] {}", end),
+            EventKind::Error { error } => info!("[Error] {}", error),
+        }
+    }
+}
+
+/// The subscribers of an agent, which the agents it delegates to share
+#[derive(Clone, Default)]
+pub struct Events {
+    agent: Option<String>,
+    handlers: Vec<Arc<dyn EventHandler>>,
+}
+
+impl Events {
+    pub fn subscribe(&mut self, handler: Arc<dyn EventHandler>) {
+        self.handlers.push(handler);
+    }
+
+    /// The events of an agent adopting the persona, delegated to by this one
+    pub fn child(&self, persona: &str) -> Self {
+        Self {
+            agent: Some(match &self.agent {
+                Some(agent) => format!("{}/{}", agent, persona),
+                None => persona.to_string(),
+            }),
+            handlers: self.handlers.clone(),
+        }
+    }
+
+    pub fn emit(&self, kind: EventKind) {
+        let event = Event {
+            agent: self.agent.clone(),
+            kind,
+        };
+        for handler in &self.handlers {
+            handler.handle(&event);
+        }
+    }
+
+    pub fn progress(&self, text: &str) {
+        self.emit(EventKind::Progress {
+            text: text.to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Event>>);
+
+    impl EventHandler for Recorder {
+        fn handle(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    impl Recorder {
+        fn agents(&self) -> Vec<Option<String>> {
+            self.0
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|event| event.agent.clone())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn sends_the_events_to_all_subscribers() {
+        let first = Arc::new(Recorder::default());
+        let second = Arc::new(Recorder::default());
+        let mut events = Events::default();
+        events.subscribe(first.clone());
+        events.subscribe(second.clone());
+        events.progress("Calling the MCP tool `search`");
+        events.emit(EventKind::ModelRequest { step: 1 });
+        assert_eq!(first.agents(), vec![None, None]);
+        assert_eq!(second.agents(), vec![None, None]);
+    }
+
+    #[test]
+    fn names_the_agents_delegated_to() {
+        let recorder = Arc::new(Recorder::default());
+        let mut events = Events::default();
+        events.subscribe(recorder.clone());
+        let coder = events.child("coder");
+        let reviewer = coder.child("reviewer");
+        events.progress("planning");
+        coder.progress("coding");
+        reviewer.progress("reviewing");
+        assert_eq!(
+            recorder.agents(),
+            vec![
+                None,
+                Some("coder".to_string()),
+                Some("coder/reviewer".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn serializes_the_kind_with_its_fields() {
+        let event = Event {
+            agent: Some("coder".to_string()),
+            kind: EventKind::CommandFinished {
+                command: "cargo test".to_string(),
+                status: "exit status: 1".to_string(),
+                success: false,
+                lines: 12,
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "agent": "coder",
+                "type": "command-finished",
+                "command": "cargo test",
+                "status": "exit status: 1",
+                "success": false,
+                "lines": 12,
+            })
+        );
+    }
+
+    #[test]
+    fn sends_the_events_to_a_channel() {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut events = Events::default();
+        events.subscribe(Arc::new(sender));
+        events.emit(EventKind::Message {
+            text: "Done".to_string(),
+        });
+        let event = receiver.try_recv().unwrap();
+        assert!(matches!(event.kind, EventKind::Message { text } if text == "Done"));
+        drop(events);
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,327 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read};
+use std::path::Path;
+
+pub const DEFAULT_MAX_BYTES: usize = 64 * 1024;
+
+/// Gemini rejects requests with more than 20MB of inline data, counted after base64 encoding.
+pub const MAX_MEDIA_BYTES: usize = 20 * 1024 * 1024;
+
+/// How many bytes at the start of a file are checked for NUL bytes
+/// when deciding whether the file is binary.
+const BINARY_SNIFF_LEN: usize = 8000;
+
+/// Reads the file at `path` and returns the response for the model.
+/// Lines are numbered starting from 1. Only the lines from `start` to `end` (inclusive) are returned
+/// and the output stops once it grows beyond `max_bytes`, telling the model where to continue from.
+/// Binary files are described by their type and size instead of their content.
+pub fn read(path: &str, start: Option<usize>, end: Option<usize>, max_bytes: usize) -> String {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => return read_error(path, e),
+    };
+    let size = match file.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(e) => return read_error(path, e),
+    };
+    render(path, BufReader::new(file), size, start, end, max_bytes)
+}
+
+/// Same as [`read`] but reading the content of the file, of `size` bytes, from `reader`.
+/// The content is read line by line, only the lines that are shown are kept.
+pub fn render(
+    path: &str,
+    mut reader: impl BufRead,
+    size: u64,
+    start: Option<usize>,
+    end: Option<usize>,
+    max_bytes: usize,
+) -> String {
+    let mut head = Vec::new();
+    if let Err(e) = reader
+        .by_ref()
+        .take(BINARY_SNIFF_LEN as u64)
+        .read_to_end(&mut head)
+    {
+        return read_error(path, e);
+    }
+    if is_binary(&head) {
+        return format!(
+            "The file `{}` is a binary file ({}, {} bytes). Its content is not shown.",
+            path,
+            binary_type(&head),
+            size
+        );
+    }
+    let mut reader = head.as_slice().chain(reader);
+
+    let start = start.unwrap_or(1).max(1);
+    let requested_end = end.unwrap_or(usize::MAX);
+    let mut output = Vec::new();
+    let mut output_size = 0;
+    let mut last = start - 1;
+    // whether the output reached `max_bytes`, the following lines are only counted
+    let mut full = false;
+    let mut cut_line = None;
+    let mut total = 0;
+    let mut offset = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = match reader.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) => return read_error(path, e),
+        };
+        let text = match std::str::from_utf8(&line) {
+            Ok(text) => text,
+            Err(e) => {
+                return format!(
+                    "The file `{}` is not valid UTF-8 (invalid byte sequence at byte {}). Its content is not shown.",
+                    path,
+                    offset + e.valid_up_to()
+                );
+            }
+        };
+        offset += read;
+        total += 1;
+        if full || total < start || total > requested_end {
+            continue;
+        }
+        let text = match text.strip_suffix('\n') {
+            Some(text) => text.strip_suffix('\r').unwrap_or(text),
+            None => text,
+        };
+        let numbered = format!("{}: {}", total, text);
+        if output_size + numbered.len() > max_bytes {
+            full = true;
+            if !output.is_empty() {
+                continue;
+            }
+            // a line longer than `max_bytes` is cut, e.g. the single line of a minified file
+            let mut cut = max_bytes;
+            while !numbered.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            output.push(numbered[..cut].to_string());
+            cut_line = Some(total);
+            last = total;
+            continue;
+        }
+        output_size += numbered.len() + 1;
+        output.push(numbered);
+        last = total;
+    }
+
+    if total == 0 {
+        return format!("The file `{}` is empty.", path);
+    }
+    let end = requested_end.min(total);
+    if start > end {
+        return format!(
+            "The file `{}` has {} lines, there is nothing to show from line {} to line {}.",
+            path, total, start, end
+        );
+    }
+    let mut response = format!(
+        "The content of the file `{}` (lines {}-{} of {}) is:\n```\n{}\n```",
+        path,
+        start,
+        last,
+        total,
+        output.join("\n")
+    );
+    if let Some(cut_line) = cut_line {
+        response.push_str(&format!(
+            "\nLine {} was cut at {} bytes. Read it with a larger `max_bytes` to see all of it.",
+            cut_line, max_bytes
+        ));
+    }
+    if last < end {
+        response.push_str(&format!(
+            "\nThe output was truncated at {} bytes. Read again with `start: {}` to see more.",
+            max_bytes,
+            last + 1
+        ));
+    }
+    response
+}
+
+/// Returns the mime type of files that are sent to the model as is instead of as text.
+pub fn media_type(path: &str) -> Option<&'static str> {
+    let extension = Path::new(path).extension()?.to_str()?.to_lowercase();
+    match extension.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "webp" => Some("image/webp"),
+        "pdf" => Some("application/pdf"),
+        _ => None,
+    }
+}
+
+/// Reads a media file to be attached to the message for the model, which already has `attached`
+/// bytes of attachments once encoded, as the limit applies to all of them together.
+/// On failure, returns the response for the model explaining what went wrong.
+pub fn read_media(path: &str, attached: usize) -> Result<Vec<u8>, String> {
+    let bytes = fs::read(path).map_err(|e| read_error(path, e))?;
+    let encoded_len = encoded_len(bytes.len());
+    if encoded_len > MAX_MEDIA_BYTES {
+        return Err(format!(
+            "The file `{}` is too large to attach ({} bytes once encoded, the limit is {} bytes).",
+            path, encoded_len, MAX_MEDIA_BYTES
+        ));
+    }
+    if attached + encoded_len > MAX_MEDIA_BYTES {
+        return Err(format!(
+            "The file `{}` was not attached: with the files attached before it, the attachments would take {} bytes once encoded, the limit is {} bytes. Read it in a later step.",
+            path,
+            attached + encoded_len,
+            MAX_MEDIA_BYTES
+        ));
+    }
+    Ok(bytes)
+}
+
+/// The size of data once base64 encoded, as it is sent to the model: 4 bytes for every 3
+pub fn encoded_len(len: usize) -> usize {
+    len.div_ceil(3) * 4
+}
+
+fn read_error(path: &str, e: io::Error) -> String {
+    match e.kind() {
+        ErrorKind::NotFound => format!("The file `{}` does not exist.", path),
+        ErrorKind::PermissionDenied => {
+            format!("Permission denied while reading the file `{}`.", path)
+        }
+        ErrorKind::IsADirectory => format!("`{}` is a directory, not a file.", path),
+        _ => format!("Failed to read the file `{}`: {}", path, e),
+    }
+}
+
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.iter().take(BINARY_SNIFF_LEN).any(|&b| b == 0)
+}
+
+/// Guesses the type of a binary file from its magic number.
+fn binary_type(bytes: &[u8]) -> &'static str {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "PNG image"),
+        (b"\xff\xd8\xff", "JPEG image"),
+        (b"GIF8", "GIF image"),
+        (b"%PDF", "PDF document"),
+        (b"PK\x03\x04", "zip archive"),
+        (b"\x1f\x8b", "gzip archive"),
+        (b"\x7fELF", "ELF executable"),
+        (b"MZ", "Windows executable"),
+        (b"\0asm", "WebAssembly module"),
+        (b"SQLite format 3\0", "SQLite database"),
+    ];
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return "WebP image";
+    }
+    SIGNATURES
+        .iter()
+        .find(|(signature, _)| bytes.starts_with(signature))
+        .map(|(_, name)| *name)
+        .unwrap_or("unknown type")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn render_text(
+        text: &str,
+        start: Option<usize>,
+        end: Option<usize>,
+        max_bytes: usize,
+    ) -> String {
+        render(
+            "f.txt",
+            text.as_bytes(),
+            text.len() as u64,
+            start,
+            end,
+            max_bytes,
+        )
+    }
+
+    #[test]
+    fn reads_the_lines_of_the_range() {
+        let text = "one\ntwo\r\nthree\nfour";
+        assert_eq!(
+            render_text(text, Some(2), Some(3), DEFAULT_MAX_BYTES),
+            "The content of the file `f.txt` (lines 2-3 of 4) is:\n```\n2: two\n3: three\n```"
+        );
+        assert!(render_text(text, None, None, DEFAULT_MAX_BYTES).contains("(lines 1-4 of 4)"));
+        assert!(render_text(text, Some(3), Some(99), DEFAULT_MAX_BYTES).contains("\n4: four\n"));
+        assert_eq!(
+            render_text(text, Some(5), None, DEFAULT_MAX_BYTES),
+            "The file `f.txt` has 4 lines, there is nothing to show from line 5 to line 4."
+        );
+        assert_eq!(
+            render_text("", None, None, DEFAULT_MAX_BYTES),
+            "The file `f.txt` is empty."
+        );
+    }
+
+    #[test]
+    fn truncates_at_max_bytes() {
+        let text = "aaaa\nbbbb\ncccc\n";
+        let response = render_text(text, None, None, 15);
+        assert!(response.contains("(lines 1-2 of 3)"));
+        assert!(response.ends_with("Read again with `start: 3` to see more."));
+    }
+
+    #[test]
+    fn cuts_a_line_longer_than_max_bytes() {
+        let text = format!("{}\nnext", "é".repeat(100));
+        let response = render_text(&text, None, None, 10);
+        assert!(response.contains("(lines 1-1 of 2) is:\n```\n1: ééé\n```"));
+        assert!(response.contains("Line 1 was cut at 10 bytes."));
+        assert!(response.ends_with("Read again with `start: 2` to see more."));
+    }
+
+    #[test]
+    fn describes_binary_files() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(
+            render("a.png", &png[..], 1234, None, None, DEFAULT_MAX_BYTES),
+            "The file `a.png` is a binary file (PNG image, 1234 bytes). Its content is not shown."
+        );
+        let invalid = b"ok\nbad \xff\n";
+        assert!(
+            render("a.txt", &invalid[..], 8, None, None, DEFAULT_MAX_BYTES)
+                .contains("invalid byte sequence at byte 7")
+        );
+    }
+
+    #[test]
+    fn limits_the_size_of_all_the_attachments() {
+        let path = temp_dir("file_read_media").join("image.png");
+        fs::write(&path, [0; 3000]).unwrap();
+        let path = path.to_str().unwrap();
+        assert_eq!(read_media(path, 0).unwrap().len(), 3000);
+        assert!(read_media(path, MAX_MEDIA_BYTES - 4000).is_ok());
+        assert!(
+            read_media(path, MAX_MEDIA_BYTES - 3999)
+                .unwrap_err()
+                .contains("Read it in a later step.")
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reads_files() {
+        let path = temp_dir("file_read").join("notes.txt");
+        fs::write(&path, "first\nsecond\n").unwrap();
+        let path = path.to_str().unwrap();
+        assert!(read(path, Some(2), None, DEFAULT_MAX_BYTES).contains("\n2: second\n"));
+        fs::remove_file(path).unwrap();
+        assert_eq!(
+            read(path, None, None, DEFAULT_MAX_BYTES),
+            format!("The file `{}` does not exist.", path)
+        );
+    }
+}
@@ -1,7 +1,12 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use reqwest::Client;
-use serde_json::json;
+use serde_json::{Value, json};
 use std::error::Error;
 
+use crate::message::{Message, MessagePart};
+
+#[derive(Clone)]
 pub struct GeminiClient {
     api_key: String,
     client: Client,
@@ -17,7 +22,7 @@ impl GeminiClient {
 
     pub async fn generate_content(
         &self,
-        messages: &[(&str, String)],
+        messages: &[Message],
         system_prompt: &str,
     ) -> Result<String, Box<dyn Error>> {
         let response = self.client
@@ -32,11 +37,9 @@ impl GeminiClient {
                         "text": system_prompt
                     }]
                 },
-                "contents": messages.iter().map(|(role, content)| json!({
+                "contents": messages.iter().map(|(role, parts)| json!({
                     "role": role,
-                    "parts": [{
-                        "text": content
-                    }]
+                    "parts": parts.iter().map(Self::part_json).collect::<Vec<_>>()
                 })).collect::<Vec<_>>()
                 // "contents": [
                 //     {
@@ -47,7 +50,9 @@ impl GeminiClient {
                 // ]
             }))
             .send()
-            .await?;
+            .await
+            // the URL has the API key in it, which should not end up in errors shown to clients
+            .map_err(reqwest::Error::without_url)?;
 
         let result = response.json::<serde_json::Value>().await?;
 
@@ -61,9 +66,23 @@ impl GeminiClient {
             .and_then(|t| t.as_str())
             .ok_or_else(|| {
                 let error = format!("Unexpected response format: {}", result);
-                std::io::Error::new(std::io::ErrorKind::Other, error)
+                std::io::Error::other(error)
             })?;
 
         Ok(text.to_string())
     }
+
+    fn part_json(part: &MessagePart) -> Value {
+        match part {
+            MessagePart::Text(text) => json!({
+                "text": text
+            }),
+            MessagePart::InlineData { mime_type, data } => json!({
+                "inline_data": {
+                    "mime_type": mime_type,
+                    "data": BASE64.encode(data)
+                }
+            }),
+        }
+    }
 }
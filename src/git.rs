@@ -0,0 +1,176 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Maximum length of the subject line of the commits made by the agent
+const MAX_SUBJECT_LEN: usize = 72;
+const SUBJECT_PREFIX: &str = "fash: ";
+
+/// The git repository the agent is working in.
+/// Changes made by the agent are committed on a working branch, one commit per turn.
+pub struct GitWorkspace {
+    /// The commit the working branch was created from, `None` in a repository without commits
+    base: Option<String>,
+    branch: String,
+    /// The top-level directory of the work tree
+    root: PathBuf,
+}
+
+impl GitWorkspace {
+    /// Creates the working branch `fash/<session>` from the current HEAD of the repository `dir`
+    /// is in. Returns `None` if `dir` is not inside a git repository, and fails if the tracked
+    /// files have uncommitted changes, which would end up in the commits of the agent.
+    pub fn start(dir: &Path, session: &str) -> Result<Option<Self>, Box<dyn Error>> {
+        if git(dir, &["rev-parse", "--is-inside-work-tree"]).is_err() {
+            return Ok(None);
+        }
+        let root = PathBuf::from(git(dir, &["rev-parse", "--show-toplevel"])?.trim());
+        if !git(&root, &["status", "--porcelain", "--untracked-files=no"])?
+            .trim()
+            .is_empty()
+        {
+            return Err(
+                "the git repository has uncommitted changes, commit or stash them first".into(),
+            );
+        }
+        // fails in a repository without commits, where the branch starts from nothing
+        let base = git(&root, &["rev-parse", "--verify", "--quiet", "HEAD"])
+            .ok()
+            .map(|base| base.trim().to_string());
+        let branch = format!("fash/{}", session);
+        git(&root, &["checkout", "-b", &branch])?;
+        Ok(Some(Self { base, branch, root }))
+    }
+
+    pub fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    /// Commits the changes of the files written in the step, if there are any.
+    /// Other changes in the working tree, e.g. files created by commands, are left uncommitted.
+    /// The subject of the commit is the first line of `reason`.
+    /// Returns whether a commit was made.
+    pub fn commit_step(
+        &self,
+        step: u32,
+        reason: Option<&str>,
+        paths: &[PathBuf],
+    ) -> Result<bool, Box<dyn Error>> {
+        let paths = paths
+            .iter()
+            .filter_map(|path| self.relative_path(path))
+            .collect::<Vec<_>>();
+        if paths.is_empty() {
+            return Ok(false);
+        }
+        let mut add = vec!["add", "-A", "--"];
+        add.extend(paths.iter().map(String::as_str));
+        self.git(&add)?;
+        if self
+            .git(&["diff", "--cached", "--name-only"])?
+            .trim()
+            .is_empty()
+        {
+            return Ok(false);
+        }
+        let subject = reason
+            .and_then(|reason| reason.lines().find(|line| !line.trim().is_empty()))
+            .map(|line| truncate(line.trim(), MAX_SUBJECT_LEN - SUBJECT_PREFIX.len()))
+            .unwrap_or_else(|| format!("Step {}", step));
+        let message = format!(
+            "{}{}\n\nStep {} of the agent session.",
+            SUBJECT_PREFIX, subject, step
+        );
+        self.git(&["commit", "--no-verify", "-m", &message])?;
+        Ok(true)
+    }
+
+    /// Summary of all the changes on the working branch
+    pub fn diff_stat(&self) -> Result<String, Box<dyn Error>> {
+        if self
+            .git(&["rev-parse", "--verify", "--quiet", "HEAD"])
+            .is_err()
+        {
+            return Ok("No changes were committed.".to_string());
+        }
+        let base = match &self.base {
+            Some(base) => base.clone(),
+            // the empty tree, read from the empty stdin, to diff the first commits of a new repository against
+            None => self
+                .git(&["hash-object", "-t", "tree", "--stdin"])?
+                .trim()
+                .to_string(),
+        };
+        self.git(&["diff", "--stat", &base, "HEAD"])
+    }
+
+    /// Runs git in the top-level directory, which the paths given to it are relative to
+    fn git(&self, args: &[&str]) -> Result<String, Box<dyn Error>> {
+        git(&self.root, args)
+    }
+
+    /// The path relative to the top-level directory, `None` if it is outside of the work tree
+    fn relative_path(&self, path: &Path) -> Option<String> {
+        let path = std::path::absolute(path).ok()?;
+        // the file may have been deleted, so its directory is resolved instead
+        let dir = path.parent()?.canonicalize().ok()?;
+        let path = dir.join(path.file_name()?);
+        let root = self.root.canonicalize().ok()?;
+        path.strip_prefix(root)
+            .ok()
+            .map(|path| path.to_string_lossy().to_string())
+    }
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<String, Box<dyn Error>> {
+    let output = Command::new("git").arg("-C").arg(dir).args(args).output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "`git {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+fn truncate(text: &str, max_len: usize) -> String {
+    if text.chars().count() <= max_len {
+        return text.to_string();
+    }
+    let truncated = text.chars().take(max_len - 3).collect::<String>();
+    format!("{}...", truncated.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn commits_the_files_written_from_a_subdirectory() {
+        let dir = temp_dir("git_subdir");
+        let sub = dir.join("sub");
+        std::fs::create_dir_all(&sub).unwrap();
+        git(&dir, &["init", "-q"]).unwrap();
+        git(&dir, &["config", "user.name", "fash"]).unwrap();
+        git(&dir, &["config", "user.email", "fash@example.com"]).unwrap();
+
+        let workspace = GitWorkspace::start(&sub, "test").unwrap().unwrap();
+        std::fs::write(sub.join("f.txt"), "written").unwrap();
+        assert!(
+            workspace
+                .commit_step(1, Some("Write f"), &[sub.join("f.txt")])
+                .unwrap()
+        );
+        assert_eq!(
+            git(&dir, &["log", "--format=%s", "--name-only"])
+                .unwrap()
+                .split_whitespace()
+                .collect::<Vec<_>>(),
+            ["fash:", "Write", "f", "sub/f.txt"]
+        );
+        assert!(workspace.diff_stat().unwrap().contains("sub/f.txt"));
+    }
+}
@@ -0,0 +1,140 @@
+//! Shell commands configured in the `[hooks]` table of `config.toml`, run on events of the agents:
+//!
+//! ```toml
+//! [hooks]
+//! before_run_command = ["./scripts/check-command.sh"]
+//! after_file_write = ["cargo fmt"]
+//! on_end = ["notify-send fash \"$FASH_END_REASON\""]
+//! on_error = ["notify-send fash \"$FASH_ERROR\""]
+//! # seconds a hook can run before it is stopped, 60 by default
+//! timeout = 30
+//! ```
+//!
+//! Hooks are run with `sh -c` in the workspace of the agent, with `FASH_HOOK` set to the event,
+//! `FASH_SESSION` to the session, and `FASH_COMMAND`, `FASH_FILE`, `FASH_END_REASON` or
+//! `FASH_ERROR` depending on the event. What they print is added to the conversation.
+//!
+//! Only the global config can declare hooks: the hooks of a project config are ignored, so that
+//! working in a cloned repository does not run the commands it declares.
+
+use std::path::Path;
+use std::time::Duration;
+
+use tracing::info;
+
+use crate::shell;
+
+pub const BEFORE_RUN_COMMAND: &str = "before_run_command";
+pub const AFTER_FILE_WRITE: &str = "after_file_write";
+pub const ON_END: &str = "on_end";
+pub const ON_ERROR: &str = "on_error";
+
+/// Runs the hooks of the event one after the other in `dir`, with `env` as environment variables,
+/// and returns what they printed, for the model.
+/// Stops at the first hook that exits with a non-zero status, returning what it printed as the error,
+/// or that runs longer than `timeout`, which is stopped along with the processes it started.
+pub async fn run(
+    event: &str,
+    hooks: &[String],
+    dir: &Path,
+    env: &[(&str, &str)],
+    timeout: Duration,
+) -> Result<String, String> {
+    let mut outputs = vec![];
+    for hook in hooks {
+        info!("[Hook] {}: {}", event, hook);
+        let mut command = shell::command(hook, dir, false);
+        command.env("FASH_HOOK", event).envs(env.iter().copied());
+        let output = shell::output(command, timeout)
+            .await
+            .map_err(|e| format!("The hook `{}` could not be run: {}", hook, e))?
+            .ok_or_else(|| {
+                info!("[Hook] {} timed out", hook);
+                format!(
+                    "The hook `{}` was stopped after running for {} seconds.",
+                    hook,
+                    timeout.as_secs()
+                )
+            })?;
+        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            info!("[Hook] {} finished with {}", hook, output.status);
+            return Err(format!(
+                "The hook `{}` finished with {}:\n```\n{}\n```",
+                hook,
+                output.status,
+                [stdout, stderr]
+                    .into_iter()
+                    .filter(|text| !text.is_empty())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ));
+        }
+        if !stdout.is_empty() {
+            outputs.push(format!(
+                "The hook `{}` printed:\n```\n{}\n```\n",
+                hook, stdout
+            ));
+        }
+    }
+    Ok(outputs.concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn collects_the_output_of_the_hooks() {
+        let output = run(
+            AFTER_FILE_WRITE,
+            &[
+                "echo \"$FASH_HOOK $FASH_FILE\"".to_string(),
+                "true".to_string(),
+            ],
+            Path::new("."),
+            &[("FASH_FILE", "src/main.rs")],
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            output,
+            "The hook `echo \"$FASH_HOOK $FASH_FILE\"` printed:\n```\nafter_file_write src/main.rs\n```\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_at_a_failing_hook() {
+        let error = run(
+            BEFORE_RUN_COMMAND,
+            &[
+                "echo no; exit 3".to_string(),
+                "echo unreachable".to_string(),
+            ],
+            Path::new("."),
+            &[],
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.contains("exit status: 3"));
+        assert!(error.contains("no"));
+        assert!(!error.contains("unreachable"));
+    }
+
+    #[tokio::test]
+    async fn stops_a_hook_that_runs_too_long() {
+        let error = run(
+            ON_END,
+            &["sleep 10".to_string()],
+            Path::new("."),
+            &[],
+            Duration::from_millis(100),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.contains("was stopped after running"));
+    }
+}
@@ -0,0 +1,209 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use axum::Router;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{Path, Request, State};
+use axum::http::StatusCode;
+use axum::http::header::AUTHORIZATION;
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Json, Response};
+use axum::routing::{get, post};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::info;
+
+use crate::config::Config;
+use crate::message::MessagePart;
+use crate::runs::{CancelError, Run, RunEvent, Runs};
+
+/// The body of a request starting a run
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewRun {
+    task: String,
+    persona: Option<String>,
+}
+
+/// Serves a REST API for running tasks on `host:port`:
+/// - `POST /runs` with `{ "task": ..., "persona": ... }`: starts a run, returns it with its id
+/// - `GET /runs`: lists the runs
+/// - `GET /runs/<id>`: the state, messages and outcome of a run
+/// - `GET /runs/<id>/events`: the events of a run as Server-Sent Events, from its start
+/// - `POST /runs/<id>/cancel`: stops a run
+/// - `GET /runs/<id>/transcript`: the conversation of the agent of a run with the model
+///
+/// The id of a run is its session, which can be undone with `undo`.
+/// With a `token`, every request needs the header `Authorization: Bearer <token>`, otherwise any
+/// process that can reach the address can run tasks.
+pub async fn serve(
+    api_key: String,
+    host: &str,
+    port: u16,
+    token: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let config = Config::load();
+    let listener = tokio::net::TcpListener::bind((host, port)).await?;
+    let address = listener.local_addr()?;
+    let runs = Runs::new(api_key, &config).await;
+    let app = Router::new()
+        .route("/runs", get(list_runs).post(start_run))
+        .route("/runs/{id}", get(get_run))
+        .route("/runs/{id}/events", get(run_events))
+        .route("/runs/{id}/cancel", post(cancel_run))
+        .route("/runs/{id}/transcript", get(run_transcript))
+        .with_state(runs)
+        .layer(middleware::from_fn_with_state(
+            Arc::new(token.clone()),
+            authorize,
+        ));
+    info!("[HTTP server] Serving at {}", address);
+    println!("Serving the API at http://{}", address);
+    if token.is_none() {
+        eprintln!(
+            "Warning: FASH_API_TOKEN is not set, any process that can reach the API can run tasks"
+        );
+    }
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// Rejects the requests without the token, if there is one
+async fn authorize(
+    State(token): State<Arc<Option<String>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(token) = token.as_deref() {
+        let given = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if given != Some(token) {
+            return error(
+                StatusCode::UNAUTHORIZED,
+                "The header `Authorization: Bearer <token>` with the token of the server is required",
+            );
+        }
+    }
+    next.run(request).await
+}
+
+async fn start_run(
+    State(runs): State<Arc<Runs>>,
+    body: Result<Json<NewRun>, JsonRejection>,
+) -> Response {
+    let Json(new_run) = match body {
+        Ok(body) => body,
+        Err(e) => return error(StatusCode::BAD_REQUEST, &e.body_text()),
+    };
+    info!(
+        "[HTTP server] Starting {:?}: {}",
+        new_run.persona, new_run.task
+    );
+    match runs.start(None, new_run.persona, new_run.task) {
+        Ok(id) => match runs.with_run(&id, |run| run_json(&id, run)) {
+            Some(run) => (StatusCode::CREATED, Json(run)).into_response(),
+            None => not_found(&id),
+        },
+        Err(e) => error(StatusCode::BAD_REQUEST, &e),
+    }
+}
+
+async fn list_runs(State(runs): State<Arc<Runs>>) -> Json<Value> {
+    let mut list = runs.with_runs(|runs| {
+        runs.iter()
+            .map(|(id, run)| run_json(id, run))
+            .collect::<Vec<_>>()
+    });
+    list.sort_by(|a, b| a["created"].as_str().cmp(&b["created"].as_str()));
+    Json(Value::Array(list))
+}
+
+async fn get_run(State(runs): State<Arc<Runs>>, Path(id): Path<String>) -> Response {
+    match runs.with_run(&id, |run| run_json(&id, run)) {
+        Some(run) => Json(run).into_response(),
+        None => not_found(&id),
+    }
+}
+
+async fn run_events(State(runs): State<Arc<Runs>>, Path(id): Path<String>) -> Response {
+    let Some(events) = runs.subscribe(&id) else {
+        return not_found(&id);
+    };
+    let events = UnboundedReceiverStream::new(events).map(|event| {
+        // the events of the agents are named after their type, e.g. `message` or `command-started`
+        let data = match event {
+            RunEvent::Agent { event } => json!(event),
+            finished => json!(finished),
+        };
+        let name = data["type"].as_str().unwrap_or_default().to_string();
+        Event::default().event(name).json_data(data)
+    });
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+async fn cancel_run(State(runs): State<Arc<Runs>>, Path(id): Path<String>) -> Response {
+    match runs.cancel(&id) {
+        Ok(()) => get_run(State(runs), Path(id)).await,
+        Err(CancelError::NotFound) => not_found(&id),
+        Err(CancelError::Finished(state)) => error(
+            StatusCode::CONFLICT,
+            &format!("Run `{}` is already {}", id, state.as_str()),
+        ),
+    }
+}
+
+async fn run_transcript(State(runs): State<Arc<Runs>>, Path(id): Path<String>) -> Response {
+    let Some(conversation) = runs.with_run(&id, Run::conversation) else {
+        return not_found(&id);
+    };
+    let transcript = conversation
+        .iter()
+        .map(|(role, parts)| {
+            let parts = parts
+                .iter()
+                .map(|part| match part {
+                    MessagePart::Text(text) => json!({ "text": text }),
+                    MessagePart::InlineData { mime_type, data } => {
+                        json!({ "mime_type": mime_type, "bytes": data.len() })
+                    }
+                })
+                .collect::<Vec<_>>();
+            json!({ "role": role, "parts": parts })
+        })
+        .collect::<Vec<_>>();
+    Json(Value::Array(transcript)).into_response()
+}
+
+fn run_json(id: &str, run: &Run) -> Value {
+    let mut json = json!({
+        "id": id,
+        "persona": run.persona(),
+        "task": run.task(),
+        "session": run.session(),
+        "state": run.state(),
+        "created": run.created(),
+        "updated": run.updated(),
+        "messages": run.messages(),
+    });
+    if let Some(RunEvent::Finished { outcome, error, .. }) = run.finished() {
+        json["outcome"] = json!(outcome);
+        json["error"] = json!(error);
+    }
+    json
+}
+
+fn not_found(id: &str) -> Response {
+    error(StatusCode::NOT_FOUND, &format!("Run `{}` not found", id))
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+const ENTRIES_FILE: &str = "journal.jsonl";
+
+/// A record of the file changes made by the agent in one session.
+/// Every change stores a snapshot of the file before and after it so that the session, or
+/// everything after a given step, can be rolled back. Side effects of `run` commands are not tracked.
+pub struct Journal {
+    dir: PathBuf,
+    entries: Vec<JournalEntry>,
+    /// The agent that last wrote each file, by the ids of the delegations leading to it,
+    /// so that agents running at the same time cannot overwrite each other's changes
+    claims: HashMap<PathBuf, Vec<u64>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JournalEntry {
+    /// The turn of the agent in which the change was made, starting from 1
+    step: u32,
+    path: PathBuf,
+    /// Snapshot file of the content before the change, `None` if the file did not exist
+    before: Option<String>,
+    /// Snapshot file of the content after the change, `None` if the file was deleted
+    after: Option<String>,
+}
+
+/// A new unique session id, `<random number>_<timestamp>`
+pub fn new_session_id() -> String {
+    let random_number = rand::random::<u32>();
+    let timestamp = Utc::now().format("%Y-%m-%d_%H-%M-%S").to_string();
+    format!("{}_{}", random_number, timestamp)
+}
+
+impl Journal {
+    /// Creates an empty journal for a new session. Nothing is written to disk until the first change.
+    pub fn new(journal_dir: &Path, session: &str) -> Self {
+        Self {
+            dir: journal_dir.join(session),
+            entries: vec![],
+            claims: HashMap::new(),
+        }
+    }
+
+    /// Opens the journal of an earlier session. A session without changes has an empty journal.
+    pub fn open(journal_dir: &Path, session: &str) -> Result<Self, Box<dyn Error>> {
+        let dir = journal_dir.join(session);
+        let entries_path = dir.join(ENTRIES_FILE);
+        let entries = if entries_path.exists() {
+            fs::read_to_string(&entries_path)?
+                .lines()
+                .map(serde_json::from_str)
+                .collect::<Result<Vec<JournalEntry>, _>>()?
+        } else {
+            vec![]
+        };
+        Ok(Self {
+            dir,
+            entries,
+            claims: HashMap::new(),
+        })
+    }
+
+    /// Lists the sessions that have a journal, oldest first.
+    pub fn sessions(journal_dir: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+        if !journal_dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut sessions = fs::read_dir(journal_dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join(ENTRIES_FILE).exists())
+            .filter_map(|entry| entry.file_name().to_str().map(|s| s.to_string()))
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|session| {
+            fs::metadata(journal_dir.join(session).join(ENTRIES_FILE))
+                .and_then(|m| m.modified())
+                .ok()
+        });
+        Ok(sessions)
+    }
+
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries
+    }
+
+    /// Claims the file at `path` for the agent with the given delegation ids before it writes to it.
+    /// Fails if an agent that is neither an ancestor nor a descendant of it claimed the file,
+    /// i.e. an agent running at the same time has changed it.
+    pub fn claim(&mut self, path: &Path, agent: &[u64]) -> Result<(), String> {
+        let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+        if let Some(owner) = self.claims.get(&path)
+            && !owner.starts_with(agent)
+            && !agent.starts_with(owner)
+        {
+            return Err(format!(
+                "The file `{}` is being changed by another agent working at the same time.",
+                path.display()
+            ));
+        }
+        self.claims.insert(path, agent.to_vec());
+        Ok(())
+    }
+
+    /// Hands the files claimed by the finished agent, and the agents it delegated to, to the agent
+    /// that delegated to it, so that the agents running after it can change them.
+    pub fn release(&mut self, agent: &[u64]) {
+        let Some((_, parent)) = agent.split_last() else {
+            return;
+        };
+        for owner in self.claims.values_mut() {
+            if owner.starts_with(agent) {
+                *owner = parent.to_vec();
+            }
+        }
+    }
+
+    /// Records a change of the file at `path`. `before` and `after` are the contents of the file
+    /// before and after the change, `None` if the file did not exist.
+    pub fn record(
+        &mut self,
+        step: u32,
+        path: &Path,
+        before: Option<&[u8]>,
+        after: Option<&[u8]>,
+    ) -> Result<(), Box<dyn Error>> {
+        let snapshots = self.dir.join("snapshots");
+        fs::create_dir_all(&snapshots)?;
+        // entries skipped by a rollback are kept, so the count of entries may name a used snapshot
+        let index = self.next_snapshot_index();
+        let snapshot = |kind: &str, content: Option<&[u8]>| -> std::io::Result<Option<String>> {
+            let Some(content) = content else {
+                return Ok(None);
+            };
+            let name = format!("{}.{}", index, kind);
+            fs::write(snapshots.join(&name), content)?;
+            Ok(Some(name))
+        };
+        let entry = JournalEntry {
+            step,
+            path: std::path::absolute(path)?,
+            before: snapshot("before", before)?,
+            after: snapshot("after", after)?,
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(ENTRIES_FILE))?;
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Reverts the changes made in `from_step` and all the steps after it, newest first.
+    /// A file that was changed outside of the session since is skipped unless `force` is set,
+    /// and its changes stay in the journal so that they can be reverted later with `force`.
+    /// Returns a report line for every reverted or skipped change.
+    pub fn rollback(&mut self, from_step: u32, force: bool) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut report = vec![];
+        let mut reverted = vec![false; self.entries.len()];
+        for (index, entry) in self.entries.iter().enumerate().rev() {
+            if entry.step < from_step {
+                continue;
+            }
+            let current = fs::read(&entry.path).ok();
+            let after = self.snapshot(&entry.after)?;
+            if current != after && !force {
+                report.push(format!(
+                    "Skipped {} (step {}): it was modified after the session, use --force to revert anyway",
+                    entry.path.display(),
+                    entry.step
+                ));
+                continue;
+            }
+            match self.snapshot(&entry.before)? {
+                Some(before) => fs::write(&entry.path, before)?,
+                None => {
+                    if entry.path.exists() {
+                        fs::remove_file(&entry.path)?;
+                    }
+                }
+            }
+            reverted[index] = true;
+            report.push(format!(
+                "Reverted {} (step {})",
+                entry.path.display(),
+                entry.step
+            ));
+        }
+        let mut reverted = reverted.into_iter();
+        self.entries.retain(|_| !reverted.next().unwrap_or(false));
+        self.save()?;
+        Ok(report)
+    }
+
+    /// Replaces the content after the last recorded change of the file at `path`, e.g. once the
+    /// hooks run after the write formatted it, so that the change can still be reverted.
+    pub fn update_after(
+        &mut self,
+        path: &Path,
+        after: Option<&[u8]>,
+    ) -> Result<(), Box<dyn Error>> {
+        let path = std::path::absolute(path)?;
+        let index = self.next_snapshot_index();
+        let Some(entry) = self
+            .entries
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.path == path)
+        else {
+            return Err(format!("No change of {} is recorded", path.display()).into());
+        };
+        entry.after = match after {
+            Some(content) => {
+                let name = format!("{}.after", index);
+                fs::write(self.dir.join("snapshots").join(&name), content)?;
+                Some(name)
+            }
+            None => None,
+        };
+        self.save()
+    }
+
+    /// Rewrites the entries file with the remaining entries
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let mut entries = String::new();
+        for entry in &self.entries {
+            entries.push_str(&serde_json::to_string(entry)?);
+            entries.push('\n');
+        }
+        fs::write(self.dir.join(ENTRIES_FILE), entries)?;
+        Ok(())
+    }
+
+    /// The index to name the snapshots of the next change after, not used by any remaining entry
+    fn next_snapshot_index(&self) -> usize {
+        self.entries
+            .iter()
+            .flat_map(|entry| [&entry.before, &entry.after])
+            .flatten()
+            .filter_map(|name| name.split('.').next()?.parse::<usize>().ok())
+            .max()
+            .map_or(0, |index| index + 1)
+    }
+
+    fn snapshot(&self, name: &Option<String>) -> std::io::Result<Option<Vec<u8>>> {
+        name.as_ref()
+            .map(|name| fs::read(self.dir.join("snapshots").join(name)))
+            .transpose()
+    }
+}
+
+impl JournalEntry {
+    pub fn step(&self) -> u32 {
+        self.step
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A short description of the change, e.g. `created`
+    pub fn kind(&self) -> &'static str {
+        match (&self.before, &self.after) {
+            (None, Some(_)) => "created",
+            (Some(_), None) => "deleted",
+            _ => "modified",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn rolls_back_from_the_step() {
+        let dir = temp_dir("journal_from_step");
+        let file = dir.join("a.txt");
+        let created = dir.join("b.txt");
+        let mut journal = Journal::new(&dir.join("journal"), "session");
+        fs::write(&file, "one").unwrap();
+        journal.record(1, &file, None, Some(b"one")).unwrap();
+        fs::write(&file, "two").unwrap();
+        journal
+            .record(2, &file, Some(b"one"), Some(b"two"))
+            .unwrap();
+        fs::write(&created, "new").unwrap();
+        journal.record(3, &created, None, Some(b"new")).unwrap();
+
+        let report = journal.rollback(2, false).unwrap();
+        assert_eq!(report.len(), 2);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "one");
+        assert!(!created.exists());
+        let journal = Journal::open(&dir.join("journal"), "session").unwrap();
+        assert_eq!(journal.entries().len(), 1);
+        assert_eq!(journal.entries()[0].step(), 1);
+    }
+
+    #[test]
+    fn keeps_skipped_changes_for_a_forced_retry() {
+        let dir = temp_dir("journal_skipped");
+        let file = dir.join("a.txt");
+        let mut journal = Journal::new(&dir.join("journal"), "session");
+        fs::write(&file, "two").unwrap();
+        journal
+            .record(1, &file, Some(b"one"), Some(b"two"))
+            .unwrap();
+        fs::write(&file, "edited by the user").unwrap();
+
+        let report = journal.rollback(0, false).unwrap();
+        assert!(report[0].starts_with("Skipped"));
+        assert_eq!(fs::read_to_string(&file).unwrap(), "edited by the user");
+        assert_eq!(journal.entries().len(), 1);
+
+        let mut journal = Journal::open(&dir.join("journal"), "session").unwrap();
+        let report = journal.rollback(0, true).unwrap();
+        assert!(report[0].starts_with("Reverted"));
+        assert_eq!(fs::read_to_string(&file).unwrap(), "one");
+        assert!(journal.entries().is_empty());
+    }
+
+    #[test]
+    fn rolls_back_changes_updated_after_the_write() {
+        let dir = temp_dir("journal_update_after");
+        let file = dir.join("a.rs");
+        let mut journal = Journal::new(&dir.join("journal"), "session");
+        fs::write(&file, "fn a(){}").unwrap();
+        journal
+            .record(1, &file, Some(b"fn b() {}"), Some(b"fn a(){}"))
+            .unwrap();
+        // a formatter run by a hook
+        fs::write(&file, "fn a() {}").unwrap();
+        journal.update_after(&file, Some(b"fn a() {}")).unwrap();
+
+        let mut journal = Journal::open(&dir.join("journal"), "session").unwrap();
+        let report = journal.rollback(0, false).unwrap();
+        assert!(report[0].starts_with("Reverted"));
+        assert_eq!(fs::read_to_string(&file).unwrap(), "fn b() {}");
+        assert!(journal.update_after(&file, None).is_err());
+    }
+
+    #[test]
+    fn rejects_writes_of_agents_running_at_the_same_time() {
+        let dir = temp_dir("journal_claims");
+        let file = dir.join("a.txt");
+        let mut journal = Journal::new(&dir.join("journal"), "session");
+        assert!(journal.claim(&file, &[]).is_ok());
+        assert!(journal.claim(&file, &[1]).is_ok());
+        assert!(journal.claim(&file, &[1, 3]).is_ok());
+        assert!(journal.claim(&file, &[2]).is_err());
+        journal.release(&[1, 3]);
+        assert!(journal.claim(&file, &[2]).is_err());
+        journal.release(&[1]);
+        assert!(journal.claim(&file, &[2]).is_ok());
+        assert!(journal.claim(&file, &[]).is_ok());
+    }
+
+    #[test]
+    fn does_not_reuse_the_snapshots_of_kept_changes() {
+        let dir = temp_dir("journal_snapshots");
+        let kept = dir.join("kept.txt");
+        let other = dir.join("other.txt");
+        let mut journal = Journal::new(&dir.join("journal"), "session");
+        fs::write(&other, "b").unwrap();
+        journal.record(1, &other, Some(b"a"), Some(b"b")).unwrap();
+        fs::write(&kept, "two").unwrap();
+        journal
+            .record(2, &kept, Some(b"one"), Some(b"two"))
+            .unwrap();
+        fs::write(&kept, "edited by the user").unwrap();
+        journal.rollback(0, false).unwrap();
+        assert_eq!(journal.entries().len(), 1);
+
+        fs::write(&other, "c").unwrap();
+        journal.record(3, &other, Some(b"a"), Some(b"c")).unwrap();
+        journal.rollback(0, true).unwrap();
+        assert_eq!(fs::read_to_string(&kept).unwrap(), "one");
+        assert_eq!(fs::read_to_string(&other).unwrap(), "a");
+    }
+}
@@ -0,0 +1,64 @@
+//! fash as a library: an autonomous agent that runs tasks with a model, running commands and
+//! reading and writing files, to embed it in other programs without running the CLI.
+//!
+//! ```no_run
+//! use agent_base::{Agent, Permissions, Provider, RunEnd};
+//!
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut agent = Agent::builder()
+//!     .provider(Provider::Gemini {
+//!         api_key: std::env::var("GEMINI_API_KEY")?,
+//!     })
+//!     .persona("reviewer")
+//!     .policy(Permissions::default().with_read_only(true))
+//!     .workspace("/path/to/repo")
+//!     .max_steps(20)
+//!     .build()?;
+//! let outcome = agent.run("Review the changes of the last commit").await?;
+//! for message in &outcome.messages {
+//!     println!("{}", message);
+//! }
+//! if let RunEnd::BudgetExhausted { max_steps } = outcome.end {
+//!     eprintln!("Stopped after {} steps", max_steps);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod a2a;
+pub mod a2a_server;
+pub mod agent;
+pub mod agent_builder;
+mod agent_tool;
+pub mod config;
+pub mod dry_run;
+pub mod events;
+mod file_read;
+mod gemini;
+pub mod git;
+pub mod hooks;
+pub mod http_server;
+pub mod journal;
+pub mod mcp;
+pub mod mcp_server;
+pub mod message;
+pub mod orchestrator;
+pub mod output;
+pub mod permissions;
+pub mod persona;
+pub mod persona_registry;
+pub mod plugin_tool;
+mod runs;
+mod search;
+mod shell;
+pub mod task_part;
+pub mod template;
+#[cfg(test)]
+mod test_util;
+
+pub use crate::agent::{Agent, RunEnd, RunOutcome};
+pub use crate::agent_builder::{AgentBuilder, Provider};
+pub use crate::events::{Event, EventHandler, EventKind};
+pub use crate::output::{Output, Verbosity};
+pub use crate::permissions::Permissions;
+pub use crate::plugin_tool::PluginTool;
@@ -1,15 +1,18 @@
-mod agent;
-mod agent_tool;
 mod cli;
-mod config;
-mod gemini;
-mod persona;
-mod task_part;
+mod persona_command;
 
-use crate::agent::Agent;
-use crate::cli::Args;
+use agent_base::a2a::A2aAgents;
+use agent_base::agent::Agent;
+use crate::cli::{Args, Command, task_or_prompt};
+use agent_base::config::Config;
+use agent_base::dry_run::DryRun;
+use agent_base::git::GitWorkspace;
+use agent_base::journal::{self, Journal};
+use agent_base::mcp::McpServers;
+use agent_base::output::{Output, Verbosity};
+use agent_base::{a2a_server, http_server, mcp_server, orchestrator};
 use clap::Parser;
-use chrono::Utc;
+use std::sync::Arc;
 use tracing::{info, Level};
 
 #[tokio::main]
@@ -18,29 +21,154 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv::dotenv().ok();
 
     // Parse args and get task
-    let args = Args::parse();
-    let task = args.get_task()?;
-    let persona = args.persona;
+    let mut args = Args::parse();
+    let mut planner = None;
+    let task = match args.command.take() {
+        Some(Command::Undo {
+            session,
+            step,
+            force,
+        }) => return undo(session, step, force),
+        Some(Command::Persona { command }) => {
+            return persona_command::run(command, &Config::load());
+        }
+        Some(Command::McpServe) => {
+            let api_key = std::env::var("GEMINI_API_KEY")
+                .expect("Please set GEMINI_API_KEY in your .env file");
+            init_logging(&format!("mcp_{}", journal::new_session_id()))?;
+            return mcp_server::serve(api_key).await;
+        }
+        Some(Command::A2aServe { host, port }) => {
+            let api_key = std::env::var("GEMINI_API_KEY")
+                .expect("Please set GEMINI_API_KEY in your .env file");
+            init_logging(&format!("a2a_{}", journal::new_session_id()))?;
+            return a2a_server::serve(api_key, &host, port).await;
+        }
+        Some(Command::Serve { host, port }) => {
+            let api_key = std::env::var("GEMINI_API_KEY")
+                .expect("Please set GEMINI_API_KEY in your .env file");
+            let token = std::env::var("FASH_API_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty());
+            init_logging(&format!("serve_{}", journal::new_session_id()))?;
+            return http_server::serve(api_key, &host, port, token).await;
+        }
+        Some(Command::Orchestrate {
+            task,
+            planner: planner_persona,
+        }) => {
+            planner = Some(planner_persona);
+            task_or_prompt(task)?
+        }
+        None => args.get_task()?,
+    };
+    let persona = planner.clone().or(args.persona);
 
     // Get API key from environment
     let api_key =
         std::env::var("GEMINI_API_KEY").expect("Please set GEMINI_API_KEY in your .env file");
     
-    let random_number = rand::random::<u32>();
-    let timestamp = Utc::now().format("%Y-%m-%d_%H-%M-%S").to_string();
-    let my_log_file = std::fs::File::create(format!("fash_{}_{}.log", random_number, timestamp))?;
+    let session = journal::new_session_id();
+    init_logging(&session)?;
+
+    // Initialize agent and run task
+    info!("Initializing agent with persona: {}", persona.clone().unwrap_or("None".to_string()));
+    let mut agent = Agent::new(api_key, &session);
+    agent.set_output(Output::new(if args.quiet {
+        Verbosity::Quiet
+    } else if args.show_output {
+        Verbosity::ShowOutput
+    } else {
+        Verbosity::Normal
+    }));
+    let config = Config::load();
+    agent.set_mcp(Arc::new(McpServers::start(config.mcp_servers()).await));
+    agent.set_a2a(Arc::new(A2aAgents::connect(config.a2a_agents()).await));
+    agent.set_persona(persona)?;
+    agent.set_vars(args.vars.into_iter().collect());
+    if args.dry_run {
+        let artifact_dir = args
+            .artifact_dir
+            .unwrap_or_else(|| format!("fash_{}_dry_run", session).into());
+        agent.set_dry_run(DryRun::new(artifact_dir));
+    }
+    if args.git {
+        match GitWorkspace::start(&std::env::current_dir()?, &session)? {
+            Some(git) => {
+                println!("Working on branch {}", git.branch());
+                agent.set_git(git);
+            }
+            None => eprintln!("Warning: Not inside a git repository, ignoring --git"),
+        }
+    }
+    match &planner {
+        Some(planner) => orchestrator::orchestrate(&mut agent, planner, &task).await?,
+        None => agent.run(&task).await?,
+    };
+    if let Some(artifact_dir) = agent.dry_run_artifact_dir() {
+        println!(
+            "Dry run: diffs of the file changes were written to {}",
+            artifact_dir.display()
+        );
+    }
+    if agent.has_file_changes() {
+        let binary = std::env::args()
+            .next()
+            .unwrap_or_else(|| "agent-base".to_string());
+        println!(
+            "To revert the file changes of this session, run: {} undo {}",
+            binary, session
+        );
+    }
+
+    Ok(())
+}
+
+/// Writes the logs of the session to `fash_<session>.log` in the log directory
+fn init_logging(session: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let log_dir = Config::load().log_dir();
+    std::fs::create_dir_all(&log_dir)?;
+    let my_log_file = std::fs::File::create(log_dir.join(format!("fash_{}.log", session)))?;
 
     let tracing_subscriber = tracing_subscriber::fmt::Subscriber::builder()
         .with_max_level(Level::INFO)
         .with_writer(my_log_file)
         .finish();
     tracing::subscriber::set_global_default(tracing_subscriber)?;
+    Ok(())
+}
 
-    // Initialize agent and run task
-    info!("Initializing agent with persona: {}", persona.clone().unwrap_or("None".to_string()));
-    let mut agent = Agent::new(api_key);
-    agent.set_persona(persona)?;
-    agent.run(&task).await?;
-
+fn undo(
+    session: Option<String>,
+    step: Option<u32>,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let journal_dir = Config::load().journal_dir();
+    let Some(session) = session else {
+        let sessions = Journal::sessions(&journal_dir)?;
+        if sessions.is_empty() {
+            println!("No sessions with file changes.");
+        }
+        for session in sessions {
+            println!("{}", session);
+            for entry in Journal::open(&journal_dir, &session)?.entries() {
+                println!(
+                    "  step {}: {} {}",
+                    entry.step(),
+                    entry.kind(),
+                    entry.path().display()
+                );
+            }
+        }
+        return Ok(());
+    };
+    let mut journal = Journal::open(&journal_dir, &session)?;
+    if journal.entries().is_empty() {
+        println!("Nothing to undo in session {}.", session);
+        return Ok(());
+    }
+    for line in journal.rollback(step.unwrap_or(0), force)? {
+        println!("{}", line);
+    }
     Ok(())
 }
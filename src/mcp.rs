@@ -0,0 +1,476 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::process::Stdio;
+use std::time::Duration;
+
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::agent_tool::AgentTool;
+use crate::config::McpServerConfig;
+
+/// The version of the Model Context Protocol spoken with the servers
+pub const PROTOCOL_VERSION: &str = "2024-11-05";
+
+/// How long to wait for the response to a request unless the config of the server says otherwise
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+/// A Model Context Protocol server run as a child process, spoken to with JSON-RPC over its stdio
+pub struct McpClient {
+    name: String,
+    /// Requests are sent one at a time, each waiting for its response
+    connection: Mutex<Connection>,
+    /// How long to wait for the response to a request
+    timeout: Duration,
+    tools: Vec<McpTool>,
+    resources: Vec<Value>,
+    prompts: Vec<Value>,
+}
+
+struct Connection {
+    // killed when the client is dropped
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+    next_id: u64,
+}
+
+/// A tool of an MCP server
+struct McpTool {
+    name: String,
+    description: String,
+    input_schema: Value,
+}
+
+/// The MCP servers of the config, shared by an agent and the agents it delegates to
+#[derive(Default)]
+pub struct McpServers {
+    clients: Vec<McpClient>,
+}
+
+impl McpServers {
+    /// Starts the servers and lists what they offer. Servers that fail to start are reported and skipped.
+    pub async fn start(servers: &BTreeMap<String, McpServerConfig>) -> Self {
+        let mut clients = vec![];
+        for (name, server) in servers {
+            match McpClient::start(name, server).await {
+                Ok(client) => {
+                    info!(
+                        "[MCP] Started {} with {} tools, {} resources and {} prompts",
+                        name,
+                        client.tools.len(),
+                        client.resources.len(),
+                        client.prompts.len()
+                    );
+                    clients.push(client);
+                }
+                Err(e) => {
+                    warn!("Failed to start the MCP server {}: {}", name, e);
+                    eprintln!("Warning: Failed to start the MCP server `{}`: {}", name, e);
+                }
+            }
+        }
+        Self { clients }
+    }
+
+    /// The tools, resources and prompts of the servers, as advertised to the model
+    pub fn agent_tools(&self) -> Vec<AgentTool> {
+        self.clients
+            .iter()
+            .flat_map(McpClient::agent_tools)
+            .collect()
+    }
+
+    fn client(&self, server: &str) -> Result<&McpClient, String> {
+        self.clients
+            .iter()
+            .find(|client| client.name == server)
+            .ok_or_else(|| format!("There is no MCP server `{}`.", server))
+    }
+
+    /// Whether `tool` is of the form `server/tool` for a running server
+    pub fn has_tool(&self, tool: &str) -> bool {
+        tool.split_once('/').is_some_and(|(server, tool)| {
+            self.client(server)
+                .is_ok_and(|client| client.tools.iter().any(|known| known.name == tool))
+        })
+    }
+
+    /// Calls the `server/tool` tool, returning the text of its result
+    pub async fn call_tool(&self, tool: &str, arguments: &Value) -> Result<String, String> {
+        let (server, tool) = tool
+            .split_once('/')
+            .ok_or_else(|| format!("`{}` is not of the form `server/tool`.", tool))?;
+        let arguments = if arguments.is_null() {
+            json!({})
+        } else {
+            arguments.clone()
+        };
+        let result = self
+            .client(server)?
+            .request(
+                "tools/call",
+                json!({ "name": tool, "arguments": arguments }),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        let text = content_text(result.get("content"));
+        if result.get("isError") == Some(&Value::Bool(true)) {
+            return Err(text);
+        }
+        Ok(text)
+    }
+
+    /// Reads the resource with the given URI, returning its text
+    pub async fn read_resource(&self, server: &str, uri: &str) -> Result<String, String> {
+        let result = self
+            .client(server)?
+            .request("resources/read", json!({ "uri": uri }))
+            .await
+            .map_err(|e| e.to_string())?;
+        let contents = result
+            .get("contents")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+        Ok(contents
+            .iter()
+            .map(
+                |content| match content.get("text").and_then(Value::as_str) {
+                    Some(text) => text.to_string(),
+                    None => format!(
+                        "[binary content of type {}]",
+                        content
+                            .get("mimeType")
+                            .and_then(Value::as_str)
+                            .unwrap_or("unknown")
+                    ),
+                },
+            )
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    /// Gets the prompt with the given arguments, returning its messages
+    pub async fn get_prompt(
+        &self,
+        server: &str,
+        prompt: &str,
+        arguments: &Value,
+    ) -> Result<String, String> {
+        let arguments = if arguments.is_null() {
+            json!({})
+        } else {
+            arguments.clone()
+        };
+        let result = self
+            .client(server)?
+            .request(
+                "prompts/get",
+                json!({ "name": prompt, "arguments": arguments }),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        let messages = result
+            .get("messages")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+        Ok(messages
+            .iter()
+            .map(|message| {
+                format!(
+                    "{}: {}",
+                    message
+                        .get("role")
+                        .and_then(Value::as_str)
+                        .unwrap_or("user"),
+                    content_text(message.get("content"))
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n"))
+    }
+}
+
+impl McpClient {
+    /// Starts the server, initializes the session and lists its tools, resources and prompts
+    async fn start(name: &str, server: &McpServerConfig) -> Result<Self, Box<dyn Error>> {
+        let mut child = Command::new(&server.command)
+            .args(server.args.iter().flatten())
+            .envs(server.env.iter().flatten())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().ok_or("no stdin")?;
+        let stdout = BufReader::new(child.stdout.take().ok_or("no stdout")?).lines();
+        if let Some(stderr) = child.stderr.take() {
+            let name = name.to_string();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    info!("[MCP {}] {}", name, line);
+                }
+            });
+        }
+        let mut client = McpClient {
+            name: name.to_string(),
+            connection: Mutex::new(Connection {
+                _child: child,
+                stdin,
+                stdout,
+                next_id: 0,
+            }),
+            timeout: Duration::from_secs(server.timeout.unwrap_or(DEFAULT_TIMEOUT_SECS)),
+            tools: vec![],
+            resources: vec![],
+            prompts: vec![],
+        };
+        let initialized = client
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": { "name": "fash", "version": env!("CARGO_PKG_VERSION") },
+                }),
+            )
+            .await?;
+        client.notify("notifications/initialized").await?;
+        let capabilities = initialized.get("capabilities").cloned().unwrap_or_default();
+        if capabilities.get("tools").is_some() {
+            client.tools = client
+                .list("tools/list", "tools")
+                .await?
+                .into_iter()
+                .filter_map(|tool| {
+                    Some(McpTool {
+                        name: tool.get("name")?.as_str()?.to_string(),
+                        description: tool
+                            .get("description")
+                            .and_then(Value::as_str)
+                            .unwrap_or_default()
+                            .to_string(),
+                        input_schema: tool.get("inputSchema").cloned().unwrap_or_default(),
+                    })
+                })
+                .collect();
+        }
+        if capabilities.get("resources").is_some() {
+            client.resources = client.list("resources/list", "resources").await?;
+        }
+        if capabilities.get("prompts").is_some() {
+            client.prompts = client.list("prompts/list", "prompts").await?;
+        }
+        Ok(client)
+    }
+
+    /// Sends a request and waits for its response, failing if it does not come within the timeout.
+    /// Notifications and requests from the server received in the meantime are logged and declined.
+    async fn request(&self, method: &str, params: Value) -> Result<Value, Box<dyn Error>> {
+        tokio::time::timeout(self.timeout, self.request_unbounded(method, params))
+            .await
+            .map_err(|_| {
+                format!(
+                    "The MCP server `{}` did not answer `{}` within {} seconds",
+                    self.name,
+                    method,
+                    self.timeout.as_secs()
+                )
+            })?
+    }
+
+    // a response arriving after the timeout is skipped by the next request, as its id does not match
+    async fn request_unbounded(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<Value, Box<dyn Error>> {
+        let mut connection = self.connection.lock().await;
+        connection.next_id += 1;
+        let id = connection.next_id;
+        connection
+            .send(&json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+            .await?;
+        loop {
+            let line = connection
+                .stdout
+                .next_line()
+                .await?
+                .ok_or_else(|| format!("The MCP server `{}` exited", self.name))?;
+            let Ok(message) = serde_json::from_str::<Value>(&line) else {
+                info!("[MCP {}] Ignored {}", self.name, line);
+                continue;
+            };
+            if message.get("method").is_some() {
+                if let Some(request_id) = message.get("id") {
+                    let response = match message.get("method").and_then(Value::as_str) {
+                        Some("ping") => json!({ "jsonrpc": "2.0", "id": request_id, "result": {} }),
+                        _ => json!({
+                            "jsonrpc": "2.0",
+                            "id": request_id,
+                            "error": { "code": -32601, "message": "Method not found" },
+                        }),
+                    };
+                    connection.send(&response).await?;
+                } else {
+                    info!("[MCP {}] {}", self.name, line);
+                }
+                continue;
+            }
+            if message.get("id").and_then(Value::as_u64) != Some(id) {
+                continue;
+            }
+            if let Some(error) = message.get("error") {
+                return Err(format!(
+                    "The MCP server `{}` failed to handle `{}`: {}",
+                    self.name,
+                    method,
+                    error
+                        .get("message")
+                        .and_then(Value::as_str)
+                        .unwrap_or("unknown error")
+                )
+                .into());
+            }
+            return Ok(message.get("result").cloned().unwrap_or_default());
+        }
+    }
+
+    async fn notify(&self, method: &str) -> Result<(), Box<dyn Error>> {
+        self.connection
+            .lock()
+            .await
+            .send(&json!({ "jsonrpc": "2.0", "method": method }))
+            .await
+    }
+
+    /// Collects the items of every page of a list request
+    async fn list(&self, method: &str, field: &str) -> Result<Vec<Value>, Box<dyn Error>> {
+        let mut items = vec![];
+        let mut cursor: Option<Value> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let result = self.request(method, params).await?;
+            if let Some(page) = result.get(field).and_then(Value::as_array) {
+                items.extend(page.iter().cloned());
+            }
+            cursor = result
+                .get("nextCursor")
+                .filter(|cursor| !cursor.is_null())
+                .cloned();
+            if cursor.is_none() {
+                return Ok(items);
+            }
+        }
+    }
+
+    fn agent_tools(&self) -> Vec<AgentTool> {
+        let mut tools = self
+            .tools
+            .iter()
+            .map(|tool| {
+                AgentTool::new(
+                    &format!("Tool {}/{}", self.name, tool.name),
+                    &tool.description,
+                    &format!(
+                        "{{ type: 'tool-call', tool: '{}/{}', arguments: Object }}, the arguments follow the JSON schema {}",
+                        self.name, tool.name, tool.input_schema
+                    ),
+                )
+            })
+            .collect::<Vec<_>>();
+        if !self.resources.is_empty() {
+            let resources = self
+                .resources
+                .iter()
+                .map(|resource| describe(resource, "uri"))
+                .collect::<Vec<_>>();
+            tools.push(AgentTool::new(
+                &format!("Resources of {}", self.name),
+                &format!("Read one of these resources:\n{}", resources.join("\n")),
+                &format!(
+                    "{{ type: 'mcp-resource', server: '{}', uri: String }}",
+                    self.name
+                ),
+            ));
+        }
+        if !self.prompts.is_empty() {
+            let prompts = self
+                .prompts
+                .iter()
+                .map(|prompt| describe(prompt, "name"))
+                .collect::<Vec<_>>();
+            tools.push(AgentTool::new(
+                &format!("Prompts of {}", self.name),
+                &format!("Get one of these prompts:\n{}", prompts.join("\n")),
+                &format!(
+                    "{{ type: 'mcp-prompt', server: '{}', prompt: String, arguments?: Object }}",
+                    self.name
+                ),
+            ));
+        }
+        tools
+    }
+}
+
+impl Connection {
+    async fn send(&mut self, message: &Value) -> Result<(), Box<dyn Error>> {
+        let mut line = message.to_string();
+        line.push('\n');
+        self.stdin.write_all(line.as_bytes()).await?;
+        self.stdin.flush().await?;
+        Ok(())
+    }
+}
+
+/// The text of MCP content, a list of text, image and resource items
+fn content_text(content: Option<&Value>) -> String {
+    let items = match content {
+        Some(Value::Array(items)) => items.clone(),
+        Some(item) => vec![item.clone()],
+        None => vec![],
+    };
+    items
+        .iter()
+        .map(|item| match item.get("type").and_then(Value::as_str) {
+            Some("text") => item
+                .get("text")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            Some("resource") => item
+                .get("resource")
+                .and_then(|resource| resource.get("text"))
+                .and_then(Value::as_str)
+                .unwrap_or("[binary resource]")
+                .to_string(),
+            Some(kind) => format!(
+                "[{} of type {}]",
+                kind,
+                item.get("mimeType")
+                    .and_then(Value::as_str)
+                    .unwrap_or("unknown")
+            ),
+            None => item.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A line describing a resource or prompt, e.g. `- file:///README.md: The readme`
+fn describe(item: &Value, key: &str) -> String {
+    let id = item.get(key).and_then(Value::as_str).unwrap_or_default();
+    match item.get("description").and_then(Value::as_str) {
+        Some(description) => format!("- {}: {}", id, description),
+        None => format!("- {}", id),
+    }
+}
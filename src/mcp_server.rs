@@ -0,0 +1,261 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Stdout};
+use tokio::sync::Mutex;
+use tokio::task::{JoinError, JoinSet};
+use tracing::{info, warn};
+
+use crate::a2a::A2aAgents;
+use crate::agent::Agent;
+use crate::config::Config;
+use crate::journal;
+use crate::mcp::{McpServers, PROTOCOL_VERSION};
+use crate::persona_registry::PersonaRegistry;
+
+/// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+/// Serves fash over the Model Context Protocol on stdio
+struct McpServer {
+    api_key: String,
+    personas: PersonaRegistry,
+    /// The MCP servers and remote agents of the config, used by the agents running tasks
+    mcp: Arc<McpServers>,
+    a2a: Arc<A2aAgents>,
+    stdout: Mutex<Stdout>,
+}
+
+/// Serves fash over the Model Context Protocol on stdio until stdin is closed and the running requests are done,
+/// with the tools:
+/// - `run_task`: runs a task with an optional persona and returns the messages and end reason of the agent
+/// - `list_personas`: lists the personas with their descriptions
+///
+/// Requests are handled concurrently, so several tasks can run at the same time.
+/// Every task is a session of its own, which can be undone with `undo`.
+pub async fn serve(api_key: String) -> Result<(), Box<dyn Error>> {
+    let config = Config::load();
+    let server = Arc::new(McpServer {
+        api_key,
+        personas: PersonaRegistry::new(config.persona_dirs())
+            .with_cache_file(config.persona_cache_file()),
+        mcp: Arc::new(McpServers::start(config.mcp_servers()).await),
+        a2a: Arc::new(A2aAgents::connect(config.a2a_agents()).await),
+        stdout: Mutex::new(tokio::io::stdout()),
+    });
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut requests = JoinSet::new();
+    while let Some(line) = lines.next_line().await? {
+        while requests.try_join_next().is_some() {}
+        let Ok(message) = serde_json::from_str::<Value>(&line) else {
+            warn!("[MCP server] Invalid message {}", line);
+            server
+                .send(&json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": { "code": PARSE_ERROR, "message": "Parse error" },
+                }))
+                .await?;
+            continue;
+        };
+        let method = message.get("method").and_then(Value::as_str);
+        let (Some(id), Some(method)) = (message.get("id").cloned(), method) else {
+            // notifications and responses need no answer
+            info!("[MCP server] {}", line);
+            continue;
+        };
+        let method = method.to_string();
+        let params = message.get("params").cloned().unwrap_or_default();
+        let server = server.clone();
+        requests.spawn(async move {
+            // handled in a task of its own, so that the client is answered even if it panics
+            let handler = server.clone();
+            let handler_method = method.clone();
+            let handled =
+                tokio::spawn(async move { handler.handle(&handler_method, &params).await }).await;
+            let response = response(id, handled);
+            if let Err(e) = server.send(&response).await {
+                warn!("[MCP server] Failed to respond to {}: {}", method, e);
+            }
+        });
+    }
+    // finish the tasks that are still running before exiting
+    while requests.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// The JSON-RPC response to the request `id`, from the result of the task that handled it
+fn response(id: Value, handled: Result<Result<Value, (i64, String)>, JoinError>) -> Value {
+    let error = |code, message: String| {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        })
+    };
+    match handled {
+        Ok(Ok(result)) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Ok(Err((code, message))) => error(code, message),
+        Err(e) => {
+            warn!("[MCP server] Request {} failed: {}", id, e);
+            error(INTERNAL_ERROR, format!("The request failed: {}", e))
+        }
+    }
+}
+
+impl McpServer {
+    async fn handle(&self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        info!("[MCP server] {} {}", method, params);
+        match method {
+            "initialize" => Ok(json!({
+                "protocolVersion": params
+                    .get("protocolVersion")
+                    .and_then(Value::as_str)
+                    .unwrap_or(PROTOCOL_VERSION),
+                "capabilities": { "tools": {} },
+                "serverInfo": { "name": "fash", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": tools() })),
+            "tools/call" => {
+                let name = params
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                let arguments = params.get("arguments").cloned().unwrap_or_default();
+                let result = match name {
+                    "run_task" => self.run_task(&arguments).await?,
+                    "list_personas" => Ok(self.list_personas()),
+                    _ => return Err((INVALID_PARAMS, format!("Unknown tool `{}`", name))),
+                };
+                let (text, is_error) = match result {
+                    Ok(text) => (text, false),
+                    Err(e) => (e, true),
+                };
+                Ok(json!({
+                    "content": [{ "type": "text", "text": text }],
+                    "isError": is_error,
+                }))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("Method not found: {}", method))),
+        }
+    }
+
+    /// Runs the task in a new session. Invalid arguments are a protocol error,
+    /// a failing task is reported as the result of the tool.
+    async fn run_task(&self, arguments: &Value) -> Result<Result<String, String>, (i64, String)> {
+        let task = arguments
+            .get("task")
+            .and_then(Value::as_str)
+            .ok_or((INVALID_PARAMS, "`task` is required".to_string()))?;
+        let persona = arguments
+            .get("persona")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let session = journal::new_session_id();
+        info!("[MCP server] Running task in session {}", session);
+        let mut agent = Agent::new(self.api_key.clone(), &session);
+        agent.set_interactive(false);
+        agent.set_mcp(self.mcp.clone());
+        agent.set_a2a(self.a2a.clone());
+        if let Err(e) = agent.set_persona(persona) {
+            return Ok(Err(e.to_string()));
+        }
+        let outcome = agent.run(task).await.map_err(|e| e.to_string());
+        Ok(outcome.and_then(|outcome| {
+            serde_json::to_string_pretty(&json!({
+                "session": session,
+                "outcome": outcome,
+            }))
+            .map_err(|e| e.to_string())
+        }))
+    }
+
+    fn list_personas(&self) -> String {
+        self.personas
+            .personas()
+            .iter()
+            .map(|(name, persona)| format!("{} - {}", name, persona.description()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    async fn send(&self, message: &Value) -> Result<(), Box<dyn Error>> {
+        let mut line = message.to_string();
+        line.push('\n');
+        let mut stdout = self.stdout.lock().await;
+        stdout.write_all(line.as_bytes()).await?;
+        stdout.flush().await?;
+        Ok(())
+    }
+}
+
+/// The tools of the server, with the JSON schema of their arguments
+fn tools() -> Value {
+    json!([
+        {
+            "name": "run_task",
+            "description": "Runs a task with a fash agent in the current directory, optionally adopting a persona. \
+                Returns the messages of the agent, the reason it ended with and the session the file changes can be undone with.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "task": { "type": "string", "description": "What the agent should do" },
+                    "persona": { "type": "string", "description": "The persona to adopt, see list_personas" },
+                },
+                "required": ["task"],
+            },
+        },
+        {
+            "name": "list_personas",
+            "description": "Lists the personas that tasks can be run with, with their descriptions",
+            "inputSchema": { "type": "object", "properties": {} },
+        },
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn panicking_handler() -> Result<Value, (i64, String)> {
+        panic!("unexpected state")
+    }
+
+    #[tokio::test]
+    async fn answers_a_request_whose_handler_panicked() {
+        let handled = tokio::spawn(panicking_handler()).await;
+        let response = response(json!(7), handled);
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["error"]["code"], INTERNAL_ERROR);
+        assert!(
+            response["error"]["message"]
+                .as_str()
+                .unwrap()
+                .contains("panicked")
+        );
+    }
+
+    #[test]
+    fn answers_with_the_result_or_the_error() {
+        assert_eq!(
+            response(json!(1), Ok(Ok(json!({ "tools": [] })))),
+            json!({ "jsonrpc": "2.0", "id": 1, "result": { "tools": [] } })
+        );
+        assert_eq!(
+            response(
+                json!("a"),
+                Ok(Err((METHOD_NOT_FOUND, "Method not found: x".to_string())))
+            ),
+            json!({
+                "jsonrpc": "2.0",
+                "id": "a",
+                "error": { "code": METHOD_NOT_FOUND, "message": "Method not found: x" },
+            })
+        );
+    }
+}
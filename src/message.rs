@@ -0,0 +1,33 @@
+/// A message in the conversation with the model: the role and the parts of the message.
+pub type Message = (&'static str, Vec<MessagePart>);
+
+#[derive(Debug, Clone)]
+pub enum MessagePart {
+    Text(String),
+    /// Raw file content sent as is, e.g. images and PDFs
+    InlineData {
+        mime_type: &'static str,
+        data: Vec<u8>,
+    },
+}
+
+impl MessagePart {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::Text(text.into())
+    }
+}
+
+/// Replaces the attachments of the messages with a note, once the model has seen them,
+/// so that they are not sent again with every step.
+pub fn drop_attachments(messages: &mut [Message]) {
+    for (_, parts) in messages {
+        for part in parts.iter_mut() {
+            if let MessagePart::InlineData { mime_type, .. } = part {
+                *part = MessagePart::Text(format!(
+                    "[A file ({}) was attached here and has already been shown.]",
+                    mime_type
+                ));
+            }
+        }
+    }
+}
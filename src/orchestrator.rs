@@ -0,0 +1,384 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{Instrument, info, info_span};
+
+use crate::agent::{Agent, RunOutcome};
+use crate::config::Config;
+use crate::persona_registry::PersonaRegistry;
+
+/// A part of the task, assigned to a persona by the planner
+#[derive(Deserialize, Debug)]
+struct Subtask {
+    id: String,
+    persona: String,
+    task: String,
+    /// The subtasks that have to finish before this one starts
+    #[serde(default)]
+    depends_on: Vec<String>,
+}
+
+/// The subtasks the planner split the task into
+#[derive(Deserialize, Debug)]
+struct Plan {
+    subtasks: Vec<Subtask>,
+}
+
+/// The result of a subtask, as reported to the planner for aggregation
+#[derive(Serialize, Debug)]
+struct SubtaskResult {
+    id: String,
+    persona: String,
+    task: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outcome: Option<RunOutcome>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Runs the task in three phases:
+/// 1. The planner agent splits the task into a graph of subtasks assigned to personas.
+/// 2. The subtasks are run by agents adopting their personas, as soon as the subtasks they
+///    depend on are done and at most `max_concurrency` of the `[delegation]` config at a time.
+///    Subtasks depending on a failed subtask are skipped.
+/// 3. A new agent with the planner persona summarises the results.
+///
+/// The subtask agents share the journal, dry run and git branch of the planner agent. They are
+/// restricted by the policy, but not by the permissions of the planner persona, which only plans.
+pub async fn orchestrate(
+    planner: &mut Agent,
+    planner_persona: &str,
+    task: &str,
+) -> Result<RunOutcome, Box<dyn Error>> {
+    let config = Config::load();
+    let plan_outcome = planner
+        .run(&planning_task(task, planner.personas()))
+        .instrument(info_span!("plan"))
+        .await?;
+    let plan = parse_plan(&plan_outcome)?;
+    check_plan(&plan)?;
+    let events = planner.events().clone();
+    events.progress("Plan:");
+    for subtask in &plan.subtasks {
+        let after = if subtask.depends_on.is_empty() {
+            String::new()
+        } else {
+            format!(" (after {})", subtask.depends_on.join(", "))
+        };
+        events.progress(&format!(
+            "  [{}] {}: {}{}",
+            subtask.id, subtask.persona, subtask.task, after
+        ));
+    }
+
+    let results = execute(planner, task, plan, config.max_concurrent_delegations()).await?;
+
+    let mut aggregator = planner.sub_agent(planner_persona, None)?;
+    aggregator
+        .run(&format!(
+            "The task `{}` was split into subtasks, which were run by other personas. \
+            Summarise the results for the user in a message: what was done, what failed and what is left to do. \
+            Do not run the subtasks again.\n\nThe results are:\n```json\n{}\n```",
+            task,
+            serde_json::to_string_pretty(&results)?
+        ))
+        .instrument(info_span!("aggregate"))
+        .await
+}
+
+/// Asks the planner for the plan, listing the personas it can assign subtasks to
+fn planning_task(task: &str, personas: &PersonaRegistry) -> String {
+    let personas = personas
+        .personas()
+        .into_iter()
+        .map(|(name, persona)| format!("- {}: {}", name, persona.description()))
+        .collect::<Vec<_>>();
+    format!(
+        "Plan the following task, do not carry it out yourself: {}
+
+Split it into subtasks, each assigned to one of these personas:
+{}
+
+When the plan is ready, send it as your last message, containing only JSON in this format, and end the session:
+{{
+  \"subtasks\": [
+    {{ \"id\": \"a short unique id\", \"persona\": \"the persona name\", \"task\": \"what to do\", \"depends_on\": [\"ids of subtasks that have to finish first\"] }}
+  ]
+}}",
+        task,
+        personas.join("\n")
+    )
+}
+
+/// Reads the plan from the last message of the planner that contains one
+fn parse_plan(outcome: &RunOutcome) -> Result<Plan, Box<dyn Error>> {
+    outcome
+        .messages
+        .iter()
+        .rev()
+        .find_map(|message| {
+            let message = message.trim();
+            let message = message
+                .strip_prefix("```json")
+                .or_else(|| message.strip_prefix("```"))
+                .unwrap_or(message);
+            let message = message.strip_suffix("```").unwrap_or(message);
+            serde_json::from_str::<Plan>(message).ok()
+        })
+        .ok_or_else(|| "The planner did not send a plan".into())
+}
+
+/// Checks that the subtask ids are unique and that the dependencies exist and have no cycles
+fn check_plan(plan: &Plan) -> Result<(), Box<dyn Error>> {
+    if plan.subtasks.is_empty() {
+        return Err("The plan has no subtasks".into());
+    }
+    let mut ids = HashSet::new();
+    for subtask in &plan.subtasks {
+        if !ids.insert(subtask.id.as_str()) {
+            return Err(format!("The plan has more than one subtask `{}`", subtask.id).into());
+        }
+    }
+    for subtask in &plan.subtasks {
+        if let Some(unknown) = subtask
+            .depends_on
+            .iter()
+            .find(|id| !ids.contains(id.as_str()))
+        {
+            return Err(format!(
+                "The subtask `{}` depends on the unknown subtask `{}`",
+                subtask.id, unknown
+            )
+            .into());
+        }
+    }
+    // repeatedly remove the subtasks whose dependencies are all removed, what is left has a cycle
+    let mut remaining = plan.subtasks.iter().collect::<Vec<_>>();
+    let mut done = HashSet::new();
+    while !remaining.is_empty() {
+        let (ready, waiting): (Vec<_>, Vec<_>) = remaining.into_iter().partition(|subtask| {
+            subtask
+                .depends_on
+                .iter()
+                .all(|id| done.contains(id.as_str()))
+        });
+        if ready.is_empty() {
+            let cycle = waiting
+                .iter()
+                .map(|subtask| subtask.id.as_str())
+                .collect::<Vec<_>>();
+            return Err(format!(
+                "The subtasks {} depend on each other in a cycle",
+                cycle.join(", ")
+            )
+            .into());
+        }
+        done.extend(ready.iter().map(|subtask| subtask.id.as_str()));
+        remaining = waiting;
+    }
+    Ok(())
+}
+
+/// Runs the subtasks of a valid plan, returning their results in the order of the plan
+async fn execute(
+    planner: &Agent,
+    task: &str,
+    plan: Plan,
+    max_concurrency: usize,
+) -> Result<Vec<SubtaskResult>, Box<dyn Error>> {
+    let events = planner.events();
+    let semaphore = Arc::new(Semaphore::new(max_concurrency));
+    let order = plan
+        .subtasks
+        .iter()
+        .map(|subtask| subtask.id.clone())
+        .collect::<Vec<_>>();
+    let mut pending = plan.subtasks;
+    let mut results: HashMap<String, SubtaskResult> = HashMap::new();
+    let mut running = JoinSet::new();
+    while !pending.is_empty() || !running.is_empty() {
+        let (ready, waiting): (Vec<_>, Vec<_>) = pending
+            .into_iter()
+            .partition(|subtask| subtask.depends_on.iter().all(|id| results.contains_key(id)));
+        pending = waiting;
+        let mut skipped = false;
+        for subtask in ready {
+            let failed = subtask
+                .depends_on
+                .iter()
+                .find(|id| results[id.as_str()].error.is_some());
+            if let Some(failed) = failed {
+                info!("[Orchestrate] Skipped {}, {} failed", subtask.id, failed);
+                events.progress(&format!("[{}] skipped, `{}` failed", subtask.id, failed));
+                let error = format!("Not run because the subtask `{}` failed", failed);
+                results.insert(subtask.id.clone(), subtask.into_result(Err(error)));
+                skipped = true;
+                continue;
+            }
+            let prompt = subtask_prompt(task, &subtask, &results)?;
+            let agent = planner
+                .peer_agent(&subtask.persona, None)
+                .map_err(|e| e.to_string());
+            let semaphore = semaphore.clone();
+            let events = events.clone();
+            let span = info_span!("subtask", id = subtask.id, persona = subtask.persona);
+            running.spawn(
+                async move {
+                    let result = match agent {
+                        Ok(mut agent) => match semaphore.acquire_owned().await {
+                            Ok(_permit) => {
+                                events.progress(&format!(
+                                    "[{}] started by {}",
+                                    subtask.id, subtask.persona
+                                ));
+                                agent.run(&prompt).await.map_err(|e| e.to_string())
+                            }
+                            Err(e) => Err(e.to_string()),
+                        },
+                        Err(e) => Err(e),
+                    };
+                    subtask.into_result(result)
+                }
+                .instrument(span),
+            );
+        }
+        // skipping a subtask may make the ones depending on it ready to be skipped as well
+        if skipped {
+            continue;
+        }
+        let Some(result) = running.join_next().await else {
+            break;
+        };
+        let result = result?;
+        match &result.error {
+            Some(e) => events.progress(&format!("[{}] failed: {}", result.id, e)),
+            None => events.progress(&format!("[{}] done", result.id)),
+        }
+        results.insert(result.id.clone(), result);
+    }
+    Ok(order.iter().filter_map(|id| results.remove(id)).collect())
+}
+
+/// The task for the persona of the subtask, with the results of the subtasks it depends on
+fn subtask_prompt(
+    task: &str,
+    subtask: &Subtask,
+    results: &HashMap<String, SubtaskResult>,
+) -> Result<String, Box<dyn Error>> {
+    let mut prompt = format!(
+        "{}\n\nThis is a part of a larger task, which other personas work on: {}",
+        subtask.task, task
+    );
+    if !subtask.depends_on.is_empty() {
+        let dependencies = subtask
+            .depends_on
+            .iter()
+            .map(|id| &results[id])
+            .collect::<Vec<_>>();
+        prompt.push_str(&format!(
+            "\n\nThe results of the subtasks this one builds on are:\n```json\n{}\n```",
+            serde_json::to_string_pretty(&dependencies)?
+        ));
+    }
+    Ok(prompt)
+}
+
+impl Subtask {
+    fn into_result(self, result: Result<RunOutcome, String>) -> SubtaskResult {
+        SubtaskResult {
+            id: self.id,
+            persona: self.persona,
+            task: self.task,
+            error: result.as_ref().err().cloned(),
+            outcome: result.ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::RunEnd;
+
+    fn plan(subtasks: &[(&str, &[&str])]) -> Plan {
+        Plan {
+            subtasks: subtasks
+                .iter()
+                .map(|(id, depends_on)| Subtask {
+                    id: id.to_string(),
+                    persona: "coder".to_string(),
+                    task: format!("Do {}", id),
+                    depends_on: depends_on.iter().map(|id| id.to_string()).collect(),
+                })
+                .collect(),
+        }
+    }
+
+    fn check(subtasks: &[(&str, &[&str])]) -> Result<(), String> {
+        check_plan(&plan(subtasks)).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn accepts_a_graph_of_subtasks() {
+        assert_eq!(
+            check(&[("a", &[]), ("b", &["a"]), ("c", &["a"]), ("d", &["b", "c"])]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_an_empty_plan() {
+        assert_eq!(check(&[]), Err("The plan has no subtasks".to_string()));
+    }
+
+    #[test]
+    fn rejects_duplicate_ids() {
+        assert_eq!(
+            check(&[("a", &[]), ("a", &[])]),
+            Err("The plan has more than one subtask `a`".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_dependencies() {
+        assert_eq!(
+            check(&[("a", &["z"])]),
+            Err("The subtask `a` depends on the unknown subtask `z`".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        assert_eq!(
+            check(&[("a", &[]), ("b", &["c"]), ("c", &["b"])]),
+            Err("The subtasks b, c depend on each other in a cycle".to_string())
+        );
+        assert_eq!(
+            check(&[("a", &["a"])]),
+            Err("The subtasks a depend on each other in a cycle".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_the_last_plan_sent_by_the_planner() {
+        let outcome = RunOutcome {
+            messages: vec![
+                "Let me look at the code first.".to_string(),
+                "```json\n{ \"subtasks\": [{ \"id\": \"a\", \"persona\": \"coder\", \"task\": \"Do a\" }] }\n```"
+                    .to_string(),
+                "The plan is ready.".to_string(),
+            ],
+            end: RunEnd::Ended {
+                reason: "planned".to_string(),
+            },
+            steps: 2,
+        };
+        let plan = parse_plan(&outcome).unwrap();
+        assert_eq!(plan.subtasks.len(), 1);
+        assert!(plan.subtasks[0].depends_on.is_empty());
+    }
+}
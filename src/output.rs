@@ -0,0 +1,86 @@
+use crate::events::{Event, EventHandler, EventKind};
+
+/// How much of the work of the agents is shown in the terminal
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Verbosity {
+    /// Only the messages of the agents and the final results
+    Quiet,
+    /// Commands are shown with a summary of their output
+    #[default]
+    Normal,
+    /// Commands are shown with their full output
+    ShowOutput,
+}
+
+/// Shows what the agents do in the terminal.
+/// The output of delegated agents is prefixed with their persona, so that agents running
+/// at the same time can be told apart.
+#[derive(Clone, Debug, Default)]
+pub struct Output {
+    verbosity: Verbosity,
+}
+
+impl Output {
+    pub fn new(verbosity: Verbosity) -> Self {
+        Self { verbosity }
+    }
+
+    /// What the agent is doing, hidden in quiet mode
+    fn progress(&self, prefix: &str, text: &str) {
+        if matches!(self.verbosity, Verbosity::Normal | Verbosity::ShowOutput) {
+            println!("{}{}", prefix, text);
+        }
+    }
+}
+
+impl EventHandler for Output {
+    fn handle(&self, event: &Event) {
+        let prefix = event
+            .agent
+            .as_ref()
+            .map(|agent| format!("[{}] ", agent))
+            .unwrap_or_default();
+        match &event.kind {
+            EventKind::Message { text } => println!("{}Bot: {}", prefix, text),
+            EventKind::Progress { text } => self.progress(&prefix, text),
+            EventKind::CommandStarted { command } => {
+                self.progress(&prefix, &format!("$ {}", command))
+            }
+            // a line printed by a command, only shown with `--show-output`
+            EventKind::CommandOutput { line, stderr } => {
+                if self.verbosity != Verbosity::ShowOutput {
+                    return;
+                }
+                if *stderr {
+                    eprintln!("{}{}", prefix, line);
+                } else {
+                    println!("{}{}", prefix, line);
+                }
+            }
+            // summarises the collapsed output of a command
+            EventKind::CommandFinished { status, lines, .. } => {
+                if self.verbosity != Verbosity::Normal {
+                    return;
+                }
+                let hidden = if *lines > 0 {
+                    format!(
+                        ", {} lines of output hidden (shown with --show-output)",
+                        lines
+                    )
+                } else {
+                    String::new()
+                };
+                println!("{}  {}{}", prefix, status, hidden);
+            }
+            EventKind::BranchChanges { branch, diff_stat } => {
+                println!("{}Changes on branch {}:\n{}", prefix, branch, diff_stat)
+            }
+            EventKind::End { end } => println!("{}Ended: {}", prefix, end),
+            // the error of the agent started by the user is shown when the CLI exits
+            EventKind::Error { error } if event.agent.is_some() => {
+                eprintln!("{}Error: {}", prefix, error)
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,608 @@
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::dry_run;
+use crate::task_part::TaskPart;
+
+/// What a persona is allowed to do. Everything is allowed unless restricted.
+/// These are enforced by the agent, not just told to the model.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Permissions {
+    /// No file writes, and only read-only commands.
+    /// Commands are recognised as read-only by [`dry_run::is_read_only`], which errs on the side
+    /// of denying but cannot know every program: restrict `allowed_commands` as well, or deny
+    /// `allow_run`, when the persona must not change anything.
+    read_only: Option<bool>,
+    /// Whether commands can be run at all
+    allow_run: Option<bool>,
+    /// Command prefixes that can be run, e.g. `cargo test` or `git`.
+    /// Every command of a pipeline or list has to match one of them, and redirections,
+    /// command substitutions and process substitutions are denied.
+    allowed_commands: Option<Vec<String>>,
+    /// Files and directories that can be read, searched and written.
+    /// Commands can access any path, so they are denied unless `allowed_commands` is set too.
+    allowed_paths: Option<Vec<String>>,
+    /// Personas that can be used as tools
+    allowed_personas: Option<Vec<String>>,
+    /// MCP servers, or `server/tool` tools, that can be used in spite of the other restrictions.
+    /// MCP tools can do anything, so without it they are denied to personas with any restriction
+    /// on commands or paths, and resources and prompts to personas restricted to some commands or paths.
+    allowed_mcp: Option<Vec<String>>,
+    /// The permissions of the agent that delegated to this one, which are enforced as well
+    #[serde(skip)]
+    parent: Option<Box<Permissions>>,
+}
+
+impl Permissions {
+    /// Overrides the fields of `self` with the ones set in `other`
+    pub fn merge(self, other: Permissions) -> Self {
+        Self {
+            read_only: other.read_only.or(self.read_only),
+            allow_run: other.allow_run.or(self.allow_run),
+            allowed_commands: other.allowed_commands.or(self.allowed_commands),
+            allowed_paths: other.allowed_paths.or(self.allowed_paths),
+            allowed_personas: other.allowed_personas.or(self.allowed_personas),
+            allowed_mcp: other.allowed_mcp.or(self.allowed_mcp),
+            parent: other.parent.or(self.parent),
+        }
+    }
+
+    /// Restricts these permissions to what `parent` allows too, for an agent delegated to by one
+    /// with the `parent` permissions, so that delegating cannot gain permissions
+    pub fn within(mut self, parent: &Permissions) -> Self {
+        self.parent = Some(Box::new(parent.clone()));
+        self
+    }
+
+    /// No file writes, and only read-only commands
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = Some(read_only);
+        self
+    }
+
+    /// Whether commands can be run at all
+    pub fn with_allow_run(mut self, allow_run: bool) -> Self {
+        self.allow_run = Some(allow_run);
+        self
+    }
+
+    /// Command prefixes that can be run, e.g. `cargo test` or `git`
+    pub fn with_allowed_commands(mut self, commands: Vec<String>) -> Self {
+        self.allowed_commands = Some(commands);
+        self
+    }
+
+    /// Files and directories that can be read, searched and written
+    pub fn with_allowed_paths(mut self, paths: Vec<String>) -> Self {
+        self.allowed_paths = Some(paths);
+        self
+    }
+
+    /// Personas that can be used as tools
+    pub fn with_allowed_personas(mut self, personas: Vec<String>) -> Self {
+        self.allowed_personas = Some(personas);
+        self
+    }
+
+    /// MCP servers, or `server/tool` tools, that can be used in spite of the other restrictions
+    pub fn with_allowed_mcp(mut self, allowed: Vec<String>) -> Self {
+        self.allowed_mcp = Some(allowed);
+        self
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only.unwrap_or(false) || self.parent.as_ref().is_some_and(|p| p.read_only())
+    }
+
+    pub fn allows_persona(&self, name: &str) -> bool {
+        self.allowed_personas
+            .as_ref()
+            .is_none_or(|allowed| allowed.iter().any(|persona| persona == name))
+            && self
+                .parent
+                .as_ref()
+                .is_none_or(|parent| parent.allows_persona(name))
+    }
+
+    fn allows_run(&self) -> bool {
+        self.allow_run.unwrap_or(true) && self.parent.as_ref().is_none_or(|p| p.allows_run())
+    }
+
+    /// Checks whether the part can be executed, returns the reason if it cannot.
+    /// Relative paths are relative to `workspace`, or to the current directory without one.
+    pub fn check(&self, part: &TaskPart, workspace: Option<&Path>) -> Result<(), String> {
+        self.check_own(part, workspace)?;
+        match &self.parent {
+            Some(parent) => parent.check(part, workspace),
+            None => Ok(()),
+        }
+    }
+
+    fn check_own(&self, part: &TaskPart, workspace: Option<&Path>) -> Result<(), String> {
+        match part {
+            TaskPart::Run { command } => self.check_own_command(command),
+            TaskPart::FileWriteAdd { path, .. } | TaskPart::FileWriteReplace { path, .. } => {
+                if self.read_only.unwrap_or(false) {
+                    return Err(format!(
+                        "Writing to `{}` is not allowed, the persona is read-only.",
+                        path
+                    ));
+                }
+                self.check_path(path, workspace)
+            }
+            TaskPart::FileRead { path, .. } => self.check_path(path, workspace),
+            TaskPart::Search { path, .. } => {
+                self.check_path(path.as_deref().unwrap_or("."), workspace)
+            }
+            TaskPart::Delegate { persona, .. } => {
+                if self.allows_persona(persona) {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "Delegating to the persona `{}` is not allowed.",
+                        persona
+                    ))
+                }
+            }
+            // the command of a tool is checked with `check_command` once it is built from the arguments
+            TaskPart::ToolCall { .. } => Ok(()),
+            TaskPart::McpResource { server, uri } => self.check_own_mcp(
+                server,
+                None,
+                &format!("Reading the MCP resource `{}` of `{}`", uri, server),
+            ),
+            TaskPart::McpPrompt { server, prompt, .. } => self.check_own_mcp(
+                server,
+                None,
+                &format!("Getting the MCP prompt `{}` of `{}`", prompt, server),
+            ),
+            TaskPart::RemoteAgent { agent, .. } => {
+                self.check_side_effects(&format!("Sending a task to the remote agent `{}`", agent))
+            }
+            TaskPart::Message { .. } | TaskPart::Reason { .. } | TaskPart::End { .. } => Ok(()),
+        }
+    }
+
+    pub fn check_command(&self, command: &str) -> Result<(), String> {
+        self.check_own_command(command)?;
+        match &self.parent {
+            Some(parent) => parent.check_command(command),
+            None => Ok(()),
+        }
+    }
+
+    fn check_own_command(&self, command: &str) -> Result<(), String> {
+        if !self.allow_run.unwrap_or(true) {
+            return Err(format!(
+                "Running `{}` is not allowed, the persona cannot run commands.",
+                command
+            ));
+        }
+        if self.read_only.unwrap_or(false) && !dry_run::is_read_only(command) {
+            return Err(format!(
+                "Running `{}` is not allowed, the persona is read-only and can only run read-only commands.",
+                command
+            ));
+        }
+        if self.allowed_paths.is_some() && self.allowed_commands.is_none() {
+            return Err(format!(
+                "Running `{}` is not allowed, the persona can only access some paths and commands are not restricted to them.",
+                command
+            ));
+        }
+        if let Some(allowed_commands) = &self.allowed_commands {
+            // redirections and process substitutions would reach files and commands that are not checked
+            let allowed = !command.contains(['`', '>', '<'])
+                && !command.contains("$(")
+                && command
+                    .split(['|', ';', '&', '\n'])
+                    .map(str::trim)
+                    .filter(|segment| !segment.is_empty())
+                    .all(|segment| {
+                        allowed_commands
+                            .iter()
+                            .any(|allowed| is_command_prefix(allowed, segment))
+                    });
+            if !allowed {
+                return Err(format!(
+                    "Running `{}` is not allowed, the persona can only run: {}.",
+                    command,
+                    allowed_commands.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// MCP tools can do anything, so they can only be called by personas that can run commands and
+    /// write files anywhere, unless they are in `allowed_mcp`. `tool` is of the form `server/tool`.
+    pub fn check_mcp_tool(&self, tool: &str) -> Result<(), String> {
+        let (server, _) = tool.split_once('/').unwrap_or((tool, ""));
+        self.check_own_mcp(
+            server,
+            Some(tool),
+            &format!("Calling the MCP tool `{}`", tool),
+        )?;
+        match &self.parent {
+            Some(parent) => parent.check_mcp_tool(tool),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the use of an MCP server, of one of its tools if `tool` is given,
+    /// or of its resources and prompts
+    fn check_own_mcp(&self, server: &str, tool: Option<&str>, action: &str) -> Result<(), String> {
+        if let Some(allowed_mcp) = &self.allowed_mcp {
+            if allowed_mcp
+                .iter()
+                .any(|allowed| allowed == server || Some(allowed.as_str()) == tool)
+            {
+                return Ok(());
+            }
+            return Err(format!(
+                "{} is not allowed, the persona can only use the MCP servers and tools: {}.",
+                action,
+                allowed_mcp.join(", ")
+            ));
+        }
+        if tool.is_some() {
+            if self.read_only.unwrap_or(false) {
+                return Err(format!(
+                    "{} is not allowed, the persona is read-only.",
+                    action
+                ));
+            }
+            if !self.allow_run.unwrap_or(true) {
+                return Err(format!(
+                    "{} is not allowed, the persona cannot run commands.",
+                    action
+                ));
+            }
+        }
+        if self.allowed_commands.is_some() || self.allowed_paths.is_some() {
+            return Err(format!(
+                "{} is not allowed, the persona can only run some commands or access some paths.",
+                action
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks an action whose effects are unknown, like calling an MCP tool or a remote agent
+    fn check_side_effects(&self, action: &str) -> Result<(), String> {
+        if self.read_only() {
+            return Err(format!(
+                "{} is not allowed, the persona is read-only.",
+                action
+            ));
+        }
+        if !self.allows_run() {
+            return Err(format!(
+                "{} is not allowed, the persona cannot run commands.",
+                action
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_path(&self, path: &str, workspace: Option<&Path>) -> Result<(), String> {
+        let Some(allowed_paths) = &self.allowed_paths else {
+            return Ok(());
+        };
+        let path = resolve(Path::new(path), workspace);
+        if allowed_paths
+            .iter()
+            .any(|allowed| path.starts_with(resolve(Path::new(allowed), workspace)))
+        {
+            return Ok(());
+        }
+        Err(format!(
+            "Accessing `{}` is not allowed, the persona can only access: {}.",
+            path.display(),
+            allowed_paths.join(", ")
+        ))
+    }
+
+    /// The restrictions to tell the model about, if there are any
+    pub fn describe(&self) -> Option<String> {
+        let mut restrictions = vec![];
+        for restriction in self.restrictions() {
+            if !restrictions.contains(&restriction) {
+                restrictions.push(restriction);
+            }
+        }
+        if restrictions.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "The following restrictions are enforced, anything else will be denied:\n{}",
+            restrictions.join("\n")
+        ))
+    }
+
+    /// The restrictions of these permissions and of the parent ones
+    fn restrictions(&self) -> Vec<String> {
+        let mut restrictions = vec![];
+        if self.read_only.unwrap_or(false) {
+            restrictions
+                .push("You cannot write to files and can only run read-only commands.".to_string());
+        }
+        if !self.allow_run.unwrap_or(true)
+            || (self.allowed_paths.is_some() && self.allowed_commands.is_none())
+        {
+            restrictions.push("You cannot run commands.".to_string());
+        }
+        if let Some(allowed_commands) = &self.allowed_commands {
+            restrictions.push(format!(
+                "You can only run commands starting with: {}, without redirections or substitutions.",
+                allowed_commands.join(", ")
+            ));
+        }
+        if let Some(allowed_paths) = &self.allowed_paths {
+            restrictions.push(format!(
+                "You can only access these files and directories: {}.",
+                allowed_paths.join(", ")
+            ));
+        }
+        if let Some(allowed_mcp) = &self.allowed_mcp {
+            restrictions.push(format!(
+                "You can only use these MCP servers and tools: {}.",
+                allowed_mcp.join(", ")
+            ));
+        } else if self.allowed_commands.is_some() || self.allowed_paths.is_some() {
+            restrictions.push("You cannot use MCP tools, resources and prompts.".to_string());
+        }
+        if let Some(parent) = &self.parent {
+            restrictions.extend(parent.restrictions());
+        }
+        restrictions
+    }
+}
+
+/// Whether `command` runs `prefix`, e.g. `cargo test` matches `cargo test --all` but not `cargo testing`
+fn is_command_prefix(prefix: &str, command: &str) -> bool {
+    let prefix = prefix.split_whitespace().collect::<Vec<_>>();
+    let command = command.split_whitespace().collect::<Vec<_>>();
+    command.starts_with(&prefix)
+}
+
+/// Makes the path absolute, relative to `workspace` if given, and resolves the symbolic links,
+/// `.` and `..` of the part of it that exists, so that a link cannot lead out of an allowed path.
+/// The rest is resolved without touching the file system, so that paths to files that do not
+/// exist yet can be checked as well.
+fn resolve(path: &Path, workspace: Option<&Path>) -> PathBuf {
+    let path = match workspace {
+        Some(workspace) => workspace.join(path),
+        None => path.to_path_buf(),
+    };
+    let path = std::path::absolute(&path).unwrap_or(path);
+    let (mut resolved, rest) = path
+        .ancestors()
+        .find_map(|existing| {
+            Some((
+                existing.canonicalize().ok()?,
+                path.strip_prefix(existing).ok()?,
+            ))
+        })
+        .unwrap_or_else(|| (PathBuf::new(), &path));
+    for component in rest.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            component => resolved.push(component),
+        }
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn run(command: &str) -> TaskPart {
+        TaskPart::Run {
+            command: command.to_string(),
+        }
+    }
+
+    fn write(path: &str) -> TaskPart {
+        TaskPart::FileWriteAdd {
+            path: path.to_string(),
+            content: String::new(),
+            start: 0,
+        }
+    }
+
+    fn read(path: &str) -> TaskPart {
+        TaskPart::FileRead {
+            path: path.to_string(),
+            start: None,
+            end: None,
+            max_bytes: None,
+        }
+    }
+
+    #[test]
+    fn allows_everything_by_default() {
+        let permissions = Permissions::default();
+        assert!(permissions.check(&run("rm -rf target"), None).is_ok());
+        assert!(permissions.check(&write("src/main.rs"), None).is_ok());
+        assert!(permissions.check_mcp_tool("deploy").is_ok());
+        assert!(permissions.describe().is_none());
+    }
+
+    #[test]
+    fn read_only_denies_writes_and_other_commands() {
+        let permissions = Permissions::default().with_read_only(true);
+        assert!(permissions.check(&write("src/main.rs"), None).is_err());
+        assert!(permissions.check(&read("src/main.rs"), None).is_ok());
+        assert!(permissions.check(&run("git status"), None).is_ok());
+        assert!(permissions.check(&run("cargo build"), None).is_err());
+        assert!(
+            permissions
+                .check(&run("git diff --output=x"), None)
+                .is_err()
+        );
+        assert!(permissions.check_mcp_tool("deploy").is_err());
+    }
+
+    #[test]
+    fn allow_run_denies_every_command() {
+        let permissions = Permissions::default().with_allow_run(false);
+        assert!(permissions.check_command("ls").is_err());
+        assert!(permissions.check(&write("src/main.rs"), None).is_ok());
+    }
+
+    #[test]
+    fn allowed_commands_match_every_command_by_prefix() {
+        let permissions = Permissions::default()
+            .with_allowed_commands(vec!["cargo test".to_string(), "git".to_string()]);
+        assert!(permissions.check_command("cargo test --all").is_ok());
+        assert!(permissions.check_command("git log | git status").is_ok());
+        assert!(permissions.check_command("cargo testing").is_err());
+        assert!(permissions.check_command("cargo test && rm -rf /").is_err());
+        assert!(permissions.check_command("git $(rm -rf /)").is_err());
+    }
+
+    #[test]
+    fn allowed_commands_deny_other_commands_and_redirections() {
+        let permissions = Permissions::default().with_allowed_commands(vec!["cat".to_string()]);
+        assert!(permissions.check_command("cat x | cat").is_ok());
+        assert!(permissions.check_command("cat x > ~/.bashrc").is_err());
+        assert!(permissions.check_command("cat < x").is_err());
+        assert!(permissions.check_command("cat x; rm -rf .").is_err());
+        assert!(permissions.check_command("cat x || rm -rf .").is_err());
+        assert!(permissions.check_command("cat x\nrm -rf .").is_err());
+        assert!(permissions.check_command("cat <(rm -rf .)").is_err());
+        assert!(permissions.check_command("cat `rm -rf .`").is_err());
+    }
+
+    #[test]
+    fn allowed_paths_are_resolved_in_the_workspace() {
+        let workspace = Path::new("/work");
+        let permissions = Permissions::default().with_allowed_paths(vec!["src".to_string()]);
+        assert!(
+            permissions
+                .check(&read("src/main.rs"), Some(workspace))
+                .is_ok()
+        );
+        assert!(
+            permissions
+                .check(&write("./src/../src/lib.rs"), Some(workspace))
+                .is_ok()
+        );
+        assert!(
+            permissions
+                .check(&read("src/../Cargo.toml"), Some(workspace))
+                .is_err()
+        );
+        assert!(
+            permissions
+                .check(&read("/etc/passwd"), Some(workspace))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn allowed_paths_cannot_be_left_through_symbolic_links() {
+        let dir = temp_dir("permissions_links");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::create_dir_all(dir.join("secrets")).unwrap();
+        std::os::unix::fs::symlink(dir.join("secrets"), dir.join("src/link")).unwrap();
+        let permissions = Permissions::default().with_allowed_paths(vec!["src".to_string()]);
+        assert!(permissions.check(&read("src/main.rs"), Some(&dir)).is_ok());
+        assert!(
+            permissions
+                .check(&write("src/new/lib.rs"), Some(&dir))
+                .is_ok()
+        );
+        assert!(
+            permissions
+                .check(&read("src/link/key"), Some(&dir))
+                .is_err()
+        );
+        assert!(
+            permissions
+                .check(&write("src/link/new.txt"), Some(&dir))
+                .is_err()
+        );
+        assert!(
+            permissions
+                .check(&read("src/link/../../src"), Some(&dir))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn allowed_paths_deny_commands_unless_they_are_restricted() {
+        let permissions = Permissions::default().with_allowed_paths(vec!["src".to_string()]);
+        assert!(permissions.check_command("cat /etc/passwd").is_err());
+        let permissions = permissions.with_allowed_commands(vec!["cargo test".to_string()]);
+        assert!(permissions.check_command("cargo test").is_ok());
+    }
+
+    #[test]
+    fn delegated_permissions_stay_within_the_parent_ones() {
+        let parent = Permissions::default()
+            .with_read_only(true)
+            .with_allowed_personas(vec!["coder".to_string(), "reviewer".to_string()]);
+        let child = Permissions::default()
+            .with_read_only(false)
+            .with_allowed_personas(vec!["reviewer".to_string(), "planner".to_string()])
+            .within(&parent);
+        assert!(child.read_only());
+        assert!(child.check(&write("src/main.rs"), None).is_err());
+        assert!(child.check_command("cargo build").is_err());
+        assert!(child.check_mcp_tool("deploy").is_err());
+        assert!(child.allows_persona("reviewer"));
+        assert!(!child.allows_persona("coder"));
+        assert!(!child.allows_persona("planner"));
+
+        let parent = Permissions::default().with_allowed_commands(vec!["cargo".to_string()]);
+        let child = Permissions::default()
+            .with_allowed_commands(vec!["cargo test".to_string(), "rm".to_string()])
+            .within(&parent);
+        assert!(child.check_command("cargo test").is_ok());
+        assert!(child.check_command("rm -rf target").is_err());
+        assert!(child.describe().unwrap().contains("starting with: cargo,"));
+    }
+
+    #[test]
+    fn restricted_personas_can_only_use_the_allowed_mcp_servers() {
+        let resource = TaskPart::McpResource {
+            server: "docs".to_string(),
+            uri: "docs://readme".to_string(),
+        };
+        let permissions = Permissions::default().with_read_only(true);
+        assert!(permissions.check_mcp_tool("github/create_issue").is_err());
+        assert!(permissions.check(&resource, None).is_ok());
+
+        let permissions = Permissions::default().with_allowed_paths(vec!["src".to_string()]);
+        assert!(permissions.check_mcp_tool("github/create_issue").is_err());
+        assert!(permissions.check(&resource, None).is_err());
+
+        let permissions = Permissions::default()
+            .with_allowed_commands(vec!["cargo".to_string()])
+            .with_allowed_mcp(vec!["docs".to_string(), "github/search".to_string()]);
+        assert!(permissions.check(&resource, None).is_ok());
+        assert!(permissions.check_mcp_tool("docs/lookup").is_ok());
+        assert!(permissions.check_mcp_tool("github/search").is_ok());
+        assert!(permissions.check_mcp_tool("github/create_issue").is_err());
+    }
+
+    #[test]
+    fn merge_overrides_the_fields_that_are_set() {
+        let permissions = Permissions::default()
+            .with_read_only(true)
+            .with_allowed_personas(vec!["reviewer".to_string()])
+            .merge(Permissions::default().with_read_only(false));
+        assert!(!permissions.read_only());
+        assert!(permissions.allows_persona("reviewer"));
+        assert!(!permissions.allows_persona("coder"));
+    }
+}
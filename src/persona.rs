@@ -1,16 +1,54 @@
-use std::{fs, path::Path};
+use std::fs;
+use std::path::{Path, PathBuf};
 
 use std::error::Error;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
+use crate::permissions::Permissions;
 
-#[derive(Deserialize, Debug)]
+/// Personas that come with fash, by file name. They can be used by name
+/// unless a persona with the same name exists in the persona directory.
+pub const BUILTIN_PERSONAS: &[(&str, &str)] = &[
+    ("coder", include_str!("../personas/coder.toml")),
+    ("reviewer", include_str!("../personas/reviewer.toml")),
+    (
+        "shell-helper",
+        include_str!("../personas/shell-helper.toml"),
+    ),
+    ("researcher", include_str!("../personas/researcher.toml")),
+    ("test-writer", include_str!("../personas/test-writer.toml")),
+    ("planner", include_str!("../personas/planner.toml")),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Persona {
     name: String,
     description: String,
     instructions: String,
     allow_personas_as_tools: Option<bool>,
+    permissions: Permissions,
+    /// The files the persona was read from, including the ones it extends and includes
+    files: Vec<PathBuf>,
+}
+
+/// A persona as written in its file, before the personas it extends and includes are merged in.
+/// A file without a name and description can only be included by other personas.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct PersonaFile {
+    name: Option<String>,
+    description: Option<String>,
+    instructions: Option<String>,
+    allow_personas_as_tools: Option<bool>,
+    permissions: Option<Permissions>,
+    /// The persona this one is based on
+    extends: Option<String>,
+    /// Personas whose instructions and settings are merged into this one, in order
+    include: Option<Vec<String>>,
+    /// The files this persona and the ones merged into it were read from
+    #[serde(skip)]
+    files: Vec<PathBuf>,
 }
 
 impl Persona {
@@ -19,12 +57,51 @@ impl Persona {
     /// - name: The name of the persona
     /// - description: A short description of the persona
     /// - instructions: The instructions for the persona
+    /// - allow_personas_as_tools: Whether the other personas can be used as tools (optional)
+    /// - permissions: What the persona is allowed to do, see [`Permissions`] (optional)
+    /// - extends: The name of a persona to inherit from (optional)
+    /// - include: The names of personas to merge into this one (optional)
     ///
-    pub fn load(persona_file_path: &Path) -> Result<Self, Box<dyn Error>> {
-        let persona_file_path = persona_file_path.with_extension("toml");
-        let persona = fs::read_to_string(persona_file_path)?;
-        let persona: Persona = toml::from_str(&persona)?;
-        Ok(persona)
+    /// The instructions of the extended persona come first, followed by the ones of the included
+    /// personas and the persona itself. Other fields, including each of the permissions,
+    /// are overridden in the same order.
+    /// Personas named in `extends` and `include` are looked up in `persona_dirs`, falling back to
+    /// the built-in ones. Name and description may be inherited as well.
+    ///
+    /// Unknown fields are rejected so that typos do not go unnoticed.
+    pub fn load(
+        persona_file_path: &Path,
+        persona_dirs: &[PathBuf],
+    ) -> Result<Self, Box<dyn Error>> {
+        PersonaFile::load(persona_file_path, persona_dirs)?.into_persona()
+    }
+
+    /// Checks that a file that can only be included in other personas, see [`is_include_only`],
+    /// can be read along with the personas it extends and includes.
+    ///
+    /// [`is_include_only`]: Self::is_include_only
+    pub fn check_include(
+        persona_file_path: &Path,
+        persona_dirs: &[PathBuf],
+    ) -> Result<(), Box<dyn Error>> {
+        PersonaFile::load(persona_file_path, persona_dirs).map(|_| ())
+    }
+
+    /// Loads the persona with the given file name from the first of `persona_dirs` that has it,
+    /// falling back to the built-in persona with that name.
+    pub fn find(persona_dirs: &[PathBuf], name: &str) -> Result<Self, Box<dyn Error>> {
+        let (source, persona) = PersonaFile::read(persona_dirs, name, &[])?;
+        persona
+            .resolve(persona_dirs, &mut vec![(name.to_string(), source)])?
+            .into_persona()
+    }
+
+    /// Whether the file only contains instructions to include in other personas
+    pub fn is_include_only(persona_file_path: &Path) -> bool {
+        fs::read_to_string(persona_file_path.with_extension("toml"))
+            .ok()
+            .and_then(|content| toml::from_str::<PersonaFile>(&content).ok())
+            .is_some_and(|persona| persona.name.is_none() && persona.description.is_none())
     }
 
     pub fn name(&self) -> &str {
@@ -34,7 +111,7 @@ impl Persona {
     pub fn description(&self) -> &str {
         &self.description
     }
-    
+
     pub fn instructions(&self) -> &str {
         &self.instructions
     }
@@ -42,4 +119,283 @@ impl Persona {
     pub fn allow_personas_as_tools(&self) -> bool {
         self.allow_personas_as_tools.unwrap_or(false)
     }
-}
\ No newline at end of file
+
+    pub fn permissions(&self) -> &Permissions {
+        &self.permissions
+    }
+
+    /// The files the persona was read from, including the ones it extends and includes.
+    /// Empty for a built-in persona that does not use any persona files.
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+}
+
+/// The TOML of the built-in persona with the given name
+pub fn builtin(name: &str) -> Option<&'static str> {
+    BUILTIN_PERSONAS
+        .iter()
+        .find(|(builtin_name, _)| *builtin_name == name)
+        .map(|(_, content)| *content)
+}
+
+/// Checks that the name only has letters, digits, `_` and `-`, so that it can be used as
+/// a file name in the persona directories and in the TOML string of a new persona as is
+pub fn validate_name(name: &str) -> Result<(), Box<dyn Error>> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(format!(
+            "Invalid persona name `{}`, use only letters, digits, `_` and `-`",
+            name
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Where a persona is read from
+#[derive(Debug, PartialEq)]
+enum Source {
+    File(PathBuf),
+    Builtin,
+}
+
+impl PersonaFile {
+    /// Reads the persona file at the given path and merges in the personas it extends and includes
+    fn load(persona_file_path: &Path, persona_dirs: &[PathBuf]) -> Result<Self, Box<dyn Error>> {
+        let persona_file_path = persona_file_path.with_extension("toml");
+        let persona = fs::read_to_string(&persona_file_path)?;
+        let mut persona: PersonaFile = toml::from_str(&persona)?;
+        persona.files = vec![persona_file_path.clone()];
+        let name = persona_file_path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        persona.resolve(
+            persona_dirs,
+            &mut vec![(name, Source::File(persona_file_path))],
+        )
+    }
+
+    /// Reads the persona with the given name from the first of `persona_dirs` that has it,
+    /// or from the built-in ones. Personas that are being loaded are skipped, so a persona
+    /// extending or including its own name refers to the next one in that order.
+    /// This allows customising the built-in personas.
+    fn read(
+        persona_dirs: &[PathBuf],
+        name: &str,
+        loading: &[(String, Source)],
+    ) -> Result<(Source, Self), Box<dyn Error>> {
+        let candidates = persona_dirs
+            .iter()
+            .map(|dir| dir.join(name).with_extension("toml"))
+            .filter(|path| path.exists())
+            .map(Source::File)
+            .chain(builtin(name).map(|_| Source::Builtin))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return Err(format!("Persona `{}` not found", name).into());
+        }
+        let Some(source) = candidates.into_iter().find(|candidate| {
+            !loading
+                .iter()
+                .any(|(loading_name, source)| loading_name == name && source == candidate)
+        }) else {
+            let chain = loading
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .chain([name])
+                .collect::<Vec<_>>();
+            return Err(format!("Persona inheritance cycle: {}", chain.join(" -> ")).into());
+        };
+        let content = match &source {
+            Source::File(path) => fs::read_to_string(path)?,
+            Source::Builtin => builtin(name).unwrap_or_default().to_string(),
+        };
+        let mut persona: PersonaFile =
+            toml::from_str(&content).map_err(|e| format!("Invalid persona `{}`: {}", name, e))?;
+        if let Source::File(path) = &source {
+            persona.files = vec![path.clone()];
+        }
+        Ok((source, persona))
+    }
+
+    /// Merges the extended and included personas into this one.
+    /// `loading` is the chain of personas being resolved, ending with this one, to detect cycles.
+    /// The name is not inherited: without one of its own, the persona is named after its file.
+    fn resolve(
+        self,
+        persona_dirs: &[PathBuf],
+        loading: &mut Vec<(String, Source)>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let name = self
+            .name
+            .clone()
+            .or_else(|| loading.last().map(|(name, _)| name.clone()));
+        let mut bases = vec![];
+        if let Some(extends) = &self.extends {
+            bases.push(extends.clone());
+        }
+        bases.extend(self.include.clone().unwrap_or_default());
+
+        let mut resolved = PersonaFile::default();
+        for base in bases {
+            let (source, persona) = PersonaFile::read(persona_dirs, &base, loading)?;
+            loading.push((base, source));
+            let persona = persona.resolve(persona_dirs, loading)?;
+            loading.pop();
+            resolved = resolved.merge(persona);
+        }
+        Ok(Self {
+            name,
+            ..resolved.merge(self)
+        })
+    }
+
+    /// Overrides the fields of `self` with the ones set in `other`, appending the instructions
+    fn merge(self, other: PersonaFile) -> Self {
+        let instructions = match (self.instructions, other.instructions) {
+            (Some(base), Some(instructions)) => Some(format!(
+                "{}\n\n{}",
+                base.trim_end(),
+                instructions.trim_start()
+            )),
+            (base, instructions) => instructions.or(base),
+        };
+        Self {
+            name: other.name.or(self.name),
+            description: other.description.or(self.description),
+            instructions,
+            allow_personas_as_tools: other
+                .allow_personas_as_tools
+                .or(self.allow_personas_as_tools),
+            permissions: match (self.permissions, other.permissions) {
+                (Some(base), Some(permissions)) => Some(base.merge(permissions)),
+                (base, permissions) => permissions.or(base),
+            },
+            extends: None,
+            include: None,
+            files: [self.files, other.files].concat(),
+        }
+    }
+
+    fn into_persona(self) -> Result<Persona, Box<dyn Error>> {
+        let missing = |field: &str| format!("missing field `{}`", field);
+        Ok(Persona {
+            name: self.name.ok_or_else(|| missing("name"))?,
+            description: self.description.ok_or_else(|| missing("description"))?,
+            instructions: self.instructions.ok_or_else(|| missing("instructions"))?,
+            allow_personas_as_tools: self.allow_personas_as_tools,
+            permissions: self.permissions.unwrap_or_default(),
+            files: self.files,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn persona_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = temp_dir(&format!("persona_{}", name));
+        for (name, content) in files {
+            fs::write(dir.join(format!("{}.toml", name)), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn merges_extended_and_included_personas_in_order() {
+        let dir = persona_dir(
+            "merge",
+            &[
+                (
+                    "base",
+                    r#"
+                    name = "base"
+                    description = "The base"
+                    instructions = "Base instructions."
+                    [permissions]
+                    read_only = true
+                    allow_run = false
+                    "#,
+                ),
+                (
+                    "style",
+                    r#"
+                    instructions = "Style instructions."
+                    [permissions]
+                    allow_run = true
+                    "#,
+                ),
+                (
+                    "child",
+                    r#"
+                    description = "The child"
+                    instructions = "Child instructions."
+                    extends = "base"
+                    include = ["style"]
+                    "#,
+                ),
+            ],
+        );
+        let persona = Persona::find(&[dir], "child").unwrap();
+        assert_eq!(persona.name(), "child");
+        assert_eq!(persona.description(), "The child");
+        assert_eq!(
+            persona.instructions(),
+            "Base instructions.\n\nStyle instructions.\n\nChild instructions."
+        );
+        assert!(persona.permissions().read_only());
+        assert!(persona.permissions().check_command("ls").is_ok());
+        assert_eq!(persona.files().len(), 3);
+    }
+
+    #[test]
+    fn extends_the_builtin_persona_of_the_same_name() {
+        let dir = persona_dir(
+            "builtin",
+            &[(
+                "coder",
+                r#"
+                extends = "coder"
+                instructions = "Use tabs."
+                "#,
+            )],
+        );
+        let persona = Persona::find(&[dir], "coder").unwrap();
+        assert!(persona.instructions().ends_with("Use tabs."));
+    }
+
+    #[test]
+    fn detects_cycles() {
+        let dir = persona_dir(
+            "cycle",
+            &[("a", r#"extends = "b""#), ("b", r#"include = ["a"]"#)],
+        );
+        let error = Persona::find(&[dir], "a").unwrap_err().to_string();
+        assert_eq!(error, "Persona inheritance cycle: a -> b -> a");
+    }
+
+    #[test]
+    fn checks_include_only_files() {
+        let dir = persona_dir(
+            "include_only",
+            &[
+                ("style", r#"instructions = "Style instructions.""#),
+                ("broken", r#"include = ["missing"]"#),
+            ],
+        );
+        let dirs = [dir.clone()];
+        assert!(Persona::is_include_only(&dir.join("style.toml")));
+        assert!(Persona::check_include(&dir.join("style.toml"), &dirs).is_ok());
+        assert!(Persona::is_include_only(&dir.join("broken.toml")));
+        let error = Persona::check_include(&dir.join("broken.toml"), &dirs).unwrap_err();
+        assert_eq!(error.to_string(), "Persona `missing` not found");
+    }
+}
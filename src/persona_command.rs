@@ -0,0 +1,202 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cli::PersonaCommand;
+use agent_base::config::{Config, PROJECT_DIR_NAME};
+use agent_base::persona::{self, BUILTIN_PERSONAS, Persona};
+use agent_base::persona_registry::{PersonaRegistry, persona_files};
+
+const TEMPLATE: &str = r#"name = "{name}"
+description = "A short description of what the persona does"
+# Whether the persona can call the other personas as tools
+allow_personas_as_tools = false
+instructions = """
+Describe how the persona should behave and what it should focus on.
+"""
+"#;
+
+/// Runs a `persona` subcommand
+pub fn run(command: PersonaCommand, config: &Config) -> Result<(), Box<dyn Error>> {
+    let persona_dir = config.persona_dir();
+    let personas =
+        PersonaRegistry::new(config.persona_dirs()).with_cache_file(config.persona_cache_file());
+    let persona_dirs = personas.persona_dirs();
+    match command {
+        PersonaCommand::List => {
+            list(&personas, config.project_dir());
+            Ok(())
+        }
+        PersonaCommand::Show { name } => {
+            if personas.path(&name).is_none()
+                && let Some(content) = persona::builtin(&name)
+            {
+                println!("# built-in persona `{}`", name);
+                print!("{}", content);
+                return Ok(());
+            }
+            let path = persona_path(&personas, &name)?;
+            println!("# {}", path.display());
+            print!("{}", fs::read_to_string(path)?);
+            Ok(())
+        }
+        PersonaCommand::New { name, project } => {
+            persona::validate_name(&name)?;
+            let persona_dir = if project {
+                let project_dir = match config.project_dir() {
+                    Some(project_dir) => project_dir.to_path_buf(),
+                    None => std::env::current_dir()?.join(PROJECT_DIR_NAME),
+                };
+                project_dir.join("personas")
+            } else {
+                persona_dir
+            };
+            let path = persona_dir.join(format!("{}.toml", name));
+            if path.exists() {
+                return Err(
+                    format!("Persona `{}` already exists at {}", name, path.display()).into(),
+                );
+            }
+            fs::create_dir_all(&persona_dir)?;
+            fs::write(&path, TEMPLATE.replace("{name}", &name))?;
+            println!("Created persona `{}` at {}", name, path.display());
+            Ok(())
+        }
+        PersonaCommand::Edit { name } => {
+            let path = persona_path(&personas, &name)?;
+            let editor = std::env::var("VISUAL")
+                .or_else(|_| std::env::var("EDITOR"))
+                .unwrap_or_else(|_| "vi".to_string());
+            let status = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!("{} \"$0\"", editor))
+                .arg(&path)
+                .status()?;
+            if !status.success() {
+                return Err(format!("Editor `{}` exited with {}", editor, status).into());
+            }
+            if let Err(e) = Persona::load(&path, persona_dirs) {
+                eprintln!("Warning: Persona `{}` is invalid: {}", name, e);
+            }
+            Ok(())
+        }
+        PersonaCommand::Validate { name } => {
+            let paths = match name {
+                Some(name) => vec![persona_path(&personas, &name)?],
+                None => {
+                    let mut paths = vec![];
+                    for persona_dir in persona_dirs {
+                        paths.extend(persona_files(persona_dir)?);
+                    }
+                    paths
+                }
+            };
+            let mut invalid = 0;
+            for path in &paths {
+                match check(path, persona_dirs) {
+                    Ok(None) => println!("ok      {}", path.display()),
+                    Ok(Some(note)) => println!("ok      {} ({})", path.display(), note),
+                    Err(e) => {
+                        invalid += 1;
+                        println!("invalid {}\n        {}", path.display(), e);
+                    }
+                }
+            }
+            if invalid > 0 {
+                return Err(format!("{} of {} personas are invalid", invalid, paths.len()).into());
+            }
+            Ok(())
+        }
+        PersonaCommand::InstallDefaults { force } => {
+            fs::create_dir_all(&persona_dir)?;
+            for (name, content) in BUILTIN_PERSONAS {
+                let path = persona_dir.join(name).with_extension("toml");
+                if path.exists() && !force {
+                    println!(
+                        "Skipped `{}`, it already exists at {}",
+                        name,
+                        path.display()
+                    );
+                    continue;
+                }
+                fs::write(&path, content)?;
+                println!("Installed `{}` at {}", name, path.display());
+            }
+            Ok(())
+        }
+        PersonaCommand::Rm { name, force } => {
+            let path = persona_path(&personas, &name)?;
+            if !force {
+                print!("Remove persona `{}` at {}? [y/N] ", name, path.display());
+                io::stdout().flush()?;
+                let mut input = String::new();
+                io::stdin().read_line(&mut input)?;
+                if !input.trim().eq_ignore_ascii_case("y") {
+                    return Ok(());
+                }
+            }
+            fs::remove_file(&path)?;
+            println!("Removed persona `{}`", name);
+            Ok(())
+        }
+    }
+}
+
+/// Lists the personas of every directory, and the built-in ones, skipping the ones that are shadowed
+fn list(personas: &PersonaRegistry, project_dir: Option<&Path>) {
+    for entry in personas.entries() {
+        let origin = match entry.path() {
+            None => " (built-in)",
+            Some(path) if project_dir.is_some_and(|project_dir| path.starts_with(project_dir)) => {
+                " (project)"
+            }
+            Some(_) => "",
+        };
+        match (entry.persona(), entry.path()) {
+            (Ok(persona), _) => {
+                println!("{} - {}{}", entry.name(), persona.description(), origin)
+            }
+            (Err(_), Some(path)) if Persona::is_include_only(path) => {
+                match Persona::check_include(path, personas.persona_dirs()) {
+                    Ok(()) => println!("{} (include only){}", entry.name(), origin),
+                    Err(e) => println!("{} (invalid: {}){}", entry.name(), e, origin),
+                }
+            }
+            (Err(e), _) => println!("{} (invalid: {}){}", entry.name(), e, origin),
+        }
+    }
+}
+
+/// Loads the persona file, or checks it can be included in other personas if it is only meant for that.
+/// Returns a note on the kind of file, `None` for a regular persona.
+fn check(path: &Path, persona_dirs: &[PathBuf]) -> Result<Option<&'static str>, Box<dyn Error>> {
+    match Persona::load(path, persona_dirs) {
+        Ok(_) => Ok(None),
+        Err(_) if Persona::is_include_only(path) => {
+            Persona::check_include(path, persona_dirs)?;
+            Ok(Some("include only"))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The path of the file of an existing persona
+fn persona_path(personas: &PersonaRegistry, name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    if let Some(path) = personas.path(name) {
+        return Ok(path);
+    }
+    if persona::builtin(name).is_some() {
+        return Err(format!(
+            "`{}` is a built-in persona, run `persona install-defaults` to get a copy to customise",
+            name
+        )
+        .into());
+    }
+    let searched = personas
+        .persona_dirs()
+        .iter()
+        .map(|persona_dir| persona_dir.display().to_string())
+        .collect::<Vec<_>>();
+    Err(format!("Persona `{}` not found in {}", name, searched.join(", ")).into())
+}
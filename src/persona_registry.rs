@@ -0,0 +1,377 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::persona::{self, BUILTIN_PERSONAS, Persona};
+
+/// The personas of the persona directories and the built-in ones, shared by the CLI, the agents
+/// and the agents they delegate to.
+/// Personas are parsed once and cached until one of the files they were read from changes, or
+/// a file is added to or removed from a persona directory, which may shadow one of those files.
+/// With a cache file, the cache is kept between runs of the same built-in personas.
+#[derive(Debug)]
+pub struct PersonaRegistry {
+    persona_dirs: Vec<PathBuf>,
+    cache: Mutex<HashMap<String, CachedPersona>>,
+    cache_file: Option<PathBuf>,
+    /// The invalid personas that were reported already, with their error
+    reported: Mutex<HashSet<(String, String)>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedPersona {
+    /// The file the persona was found in, `None` for a built-in persona
+    path: Option<PathBuf>,
+    /// The persona directories with their modification time when the persona was read
+    dirs: Vec<(PathBuf, Option<SystemTime>)>,
+    /// The files the persona was read from, with their modification time when they were read
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+    persona: Persona,
+}
+
+/// The content of the cache file
+#[derive(Deserialize)]
+struct CacheFile {
+    /// The [`builtins_hash`] of the binary that wrote the cache, whose personas may extend the
+    /// built-in ones of that binary
+    builtins: u64,
+    personas: HashMap<String, CachedPersona>,
+}
+
+/// A persona of the registry, which may be invalid
+pub struct PersonaEntry {
+    name: String,
+    path: Option<PathBuf>,
+    persona: Result<Persona, String>,
+}
+
+impl PersonaRegistry {
+    /// Personas are looked up in `persona_dirs` in order, then among the built-in ones
+    pub fn new(persona_dirs: Vec<PathBuf>) -> Self {
+        Self {
+            persona_dirs,
+            cache: Mutex::new(HashMap::new()),
+            cache_file: None,
+            reported: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Keeps the parsed personas in `cache_file` between runs, starting from the ones in it
+    pub fn with_cache_file(mut self, cache_file: PathBuf) -> Self {
+        if let Ok(content) = fs::read_to_string(&cache_file) {
+            match serde_json::from_str::<CacheFile>(&content) {
+                Ok(cache) if cache.builtins == builtins_hash() => {
+                    self.cache = Mutex::new(cache.personas)
+                }
+                Ok(_) => info!(
+                    "Ignoring the persona cache {} of other built-in personas",
+                    cache_file.display()
+                ),
+                Err(e) => warn!("Ignoring the persona cache {}: {}", cache_file.display(), e),
+            }
+        }
+        self.cache_file = Some(cache_file);
+        self
+    }
+
+    pub fn persona_dirs(&self) -> &[PathBuf] {
+        &self.persona_dirs
+    }
+
+    /// The names of the personas, without the ones shadowed by a persona of the same name
+    /// in an earlier directory, followed by the built-in ones that are not shadowed.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = vec![];
+        for persona_dir in &self.persona_dirs {
+            let paths = persona_files(persona_dir).unwrap_or_else(|e| {
+                warn!("Failed to read {}: {}", persona_dir.display(), e);
+                vec![]
+            });
+            for path in paths {
+                let name = file_name(&path);
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        for (name, _) in BUILTIN_PERSONAS {
+            if !names.iter().any(|known| known == name) {
+                names.push(name.to_string());
+            }
+        }
+        names
+    }
+
+    /// The persona with the given file name, re-read only if its files or the persona directories
+    /// changed since the last time. Names that are not valid file names, e.g. `../x`, are rejected,
+    /// as they may come from a request to one of the servers.
+    pub fn get(&self, name: &str) -> Result<Persona, Box<dyn Error>> {
+        persona::validate_name(name)?;
+        let path = self.path(name);
+        let dirs = self
+            .persona_dirs
+            .iter()
+            .map(|dir| (dir.clone(), modified_time(dir)))
+            .collect::<Vec<_>>();
+        if let Some(cached) = self.cache.lock().unwrap().get(name)
+            && cached.path == path
+            && cached.dirs == dirs
+            && cached
+                .files
+                .iter()
+                .all(|(file, modified)| modified_time(file) == *modified)
+        {
+            return Ok(cached.persona.clone());
+        }
+        let persona = Persona::find(&self.persona_dirs, name)?;
+        let files = persona
+            .files()
+            .iter()
+            .map(|file| (file.clone(), modified_time(file)))
+            .collect();
+        let mut cache = self.cache.lock().unwrap();
+        cache.insert(
+            name.to_string(),
+            CachedPersona {
+                path,
+                dirs,
+                files,
+                persona: persona.clone(),
+            },
+        );
+        if let Some(cache_file) = &self.cache_file
+            && let Err(e) = save_cache(cache_file, &cache)
+        {
+            warn!(
+                "Failed to write the persona cache {}: {}",
+                cache_file.display(),
+                e
+            );
+        }
+        Ok(persona)
+    }
+
+    /// Every persona of the registry, including the invalid ones
+    pub fn entries(&self) -> Vec<PersonaEntry> {
+        self.names()
+            .into_iter()
+            .map(|name| PersonaEntry {
+                path: self.path(&name),
+                persona: self.get(&name).map_err(|e| e.to_string()),
+                name,
+            })
+            .collect()
+    }
+
+    /// The valid personas by name. Invalid ones are skipped and reported once,
+    /// files that can only be included in other personas are skipped silently.
+    pub fn personas(&self) -> Vec<(String, Persona)> {
+        self.entries()
+            .into_iter()
+            .filter_map(|entry| match entry.persona {
+                Ok(persona) => Some((entry.name, persona)),
+                Err(e) => {
+                    let include_only = entry.path.as_deref().is_some_and(Persona::is_include_only);
+                    if !include_only
+                        && self
+                            .reported
+                            .lock()
+                            .unwrap()
+                            .insert((entry.name.clone(), e.clone()))
+                    {
+                        warn!("Skipping invalid persona `{}`: {}", entry.name, e);
+                        eprintln!("Warning: Skipping invalid persona `{}`: {}", entry.name, e);
+                    }
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// The file of the persona in the first directory that has it
+    pub fn path(&self, name: &str) -> Option<PathBuf> {
+        if persona::validate_name(name).is_err() {
+            return None;
+        }
+        self.persona_dirs
+            .iter()
+            .map(|persona_dir| persona_dir.join(name).with_extension("toml"))
+            .find(|path| path.exists())
+    }
+}
+
+impl PersonaEntry {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The file of the persona, `None` for a built-in persona
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn persona(&self) -> Result<&Persona, &str> {
+        self.persona.as_ref().map_err(String::as_str)
+    }
+}
+
+/// The persona files in the directory, sorted by name. Hidden files are skipped.
+pub fn persona_files(persona_dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    if !persona_dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut paths = fs::read_dir(persona_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "toml")
+        })
+        .filter(|path| {
+            !path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Writes the cache to a temporary file first, so that other runs never read a partial cache
+fn save_cache(
+    cache_file: &Path,
+    cache: &HashMap<String, CachedPersona>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = cache_file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let temp_file = cache_file.with_extension(format!("{}.tmp", std::process::id()));
+    let content = serde_json::json!({ "builtins": builtins_hash(), "personas": cache });
+    fs::write(&temp_file, content.to_string())?;
+    fs::rename(&temp_file, cache_file)?;
+    Ok(())
+}
+
+/// Identifies the built-in personas of this binary, which are not files whose changes can be
+/// checked, and its version, which may resolve personas differently, so that a cache written by
+/// another version of fash is not used
+fn builtins_hash() -> u64 {
+    let mut hasher = DefaultHasher::new();
+    BUILTIN_PERSONAS.hash(&mut hasher);
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    hasher.finish()
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn write_persona(dir: &Path, name: &str, content: &str) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join(format!("{}.toml", name)), content).unwrap();
+    }
+
+    const CHILD: &str = r#"
+        name = "child"
+        description = "Extends base"
+        extends = "base"
+    "#;
+
+    #[test]
+    fn rereads_personas_shadowed_by_a_new_file() {
+        let root = temp_dir("registry_shadowed");
+        let (project, global) = (root.join("project"), root.join("global"));
+        fs::create_dir_all(&project).unwrap();
+        write_persona(&global, "base", r#"instructions = "Global base.""#);
+        write_persona(&global, "child", CHILD);
+        let registry = PersonaRegistry::new(vec![project.clone(), global]);
+        assert_eq!(
+            registry.get("child").unwrap().instructions(),
+            "Global base."
+        );
+
+        write_persona(&project, "base", r#"instructions = "Project base.""#);
+        assert_eq!(
+            registry.get("child").unwrap().instructions(),
+            "Project base."
+        );
+    }
+
+    #[test]
+    fn rejects_names_outside_of_the_persona_directories() {
+        let root = temp_dir("registry_names");
+        let personas = root.join("personas");
+        write_persona(&root, "secret", r#"name = "secret""#);
+        let registry = PersonaRegistry::new(vec![personas]);
+        assert!(registry.get("../secret").is_err());
+        assert!(registry.path("../secret").is_none());
+        assert!(registry.get("coder").is_ok());
+    }
+
+    #[test]
+    fn keeps_the_cache_between_registries() {
+        let root = temp_dir("registry_cache_file");
+        let (project, global) = (root.join("project"), root.join("global"));
+        let cache_file = root.join("cache/personas.json");
+        write_persona(&global, "base", r#"instructions = "Global base.""#);
+        write_persona(&global, "child", CHILD);
+        let registry =
+            PersonaRegistry::new(vec![global.clone()]).with_cache_file(cache_file.clone());
+        assert_eq!(
+            registry.get("child").unwrap().instructions(),
+            "Global base."
+        );
+        assert!(cache_file.exists());
+
+        let registry =
+            PersonaRegistry::new(vec![global.clone()]).with_cache_file(cache_file.clone());
+        assert!(registry.cache.lock().unwrap().contains_key("child"));
+        assert_eq!(
+            registry.get("child").unwrap().instructions(),
+            "Global base."
+        );
+
+        // a cache of other built-in personas is not used
+        let stale = fs::read_to_string(&cache_file)
+            .unwrap()
+            .replace(&builtins_hash().to_string(), "0")
+            .replace("Global base.", "Stale base.");
+        fs::write(&cache_file, stale).unwrap();
+        let registry =
+            PersonaRegistry::new(vec![global.clone()]).with_cache_file(cache_file.clone());
+        assert!(registry.cache.lock().unwrap().is_empty());
+        assert_eq!(
+            registry.get("child").unwrap().instructions(),
+            "Global base."
+        );
+
+        // a project directory shadowing the global personas is a different set of directories
+        write_persona(&project, "base", r#"instructions = "Project base.""#);
+        let registry = PersonaRegistry::new(vec![project, global]).with_cache_file(cache_file);
+        assert_eq!(
+            registry.get("child").unwrap().instructions(),
+            "Project base."
+        );
+    }
+}
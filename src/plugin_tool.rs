@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::agent_tool::AgentTool;
+
+/// How long a tool can run unless its manifest says otherwise
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+static PARAMETER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{\{\s*([^{}]+?)\s*\}\}").unwrap());
+
+/// A tool defined by a manifest in a `tools` directory, which runs a command built from
+/// the arguments the model calls it with.
+///
+/// ```toml
+/// name = "test"
+/// description = "Runs the tests whose name contains the filter"
+/// command = "cargo test {{filter}}"
+/// timeout = 120
+/// return_output = true
+///
+/// [parameters]
+/// type = "object"
+/// properties.filter = { type = "string", description = "Part of the test name" }
+/// required = ["filter"]
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PluginTool {
+    name: String,
+    description: String,
+    /// JSON schema of the arguments, an object with `properties`
+    parameters: Option<Value>,
+    /// Run with `sh -c`, `{{name}}` is replaced by the shell quoted argument `name`.
+    /// Only the parameters are replaced, other placeholders are left as they are.
+    command: String,
+    /// In seconds
+    timeout: Option<u64>,
+    /// Whether the output of the command is returned to the model, or only its status
+    return_output: Option<bool>,
+}
+
+impl PluginTool {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Reads a tool from the content of its manifest
+    pub fn parse(manifest: &str) -> Result<Self, Box<dyn Error>> {
+        let tool: PluginTool = toml::from_str(manifest)?;
+        if let Some(parameters) = &tool.parameters
+            && parameters.get("type").is_some_and(|kind| kind != "object")
+        {
+            return Err("`parameters` has to be a schema of type `object`".into());
+        }
+        Ok(tool)
+    }
+
+    /// Loads the tools of every directory, skipping the ones with the name of a tool of an
+    /// earlier directory. Invalid manifests are reported and skipped.
+    pub fn load_all(tool_dirs: &[PathBuf]) -> Vec<Self> {
+        let mut tools: Vec<PluginTool> = vec![];
+        for tool_dir in tool_dirs.iter().filter(|dir| dir.exists()) {
+            let Ok(entries) = fs::read_dir(tool_dir) else {
+                continue;
+            };
+            let mut paths = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.extension()
+                        .is_some_and(|extension| extension == "toml")
+                })
+                .collect::<Vec<_>>();
+            paths.sort();
+            for path in paths {
+                match PluginTool::load(&path) {
+                    Ok(tool) if tools.iter().any(|known| known.name == tool.name) => {}
+                    Ok(tool) => tools.push(tool),
+                    Err(e) => {
+                        warn!("Skipping invalid tool {}: {}", path.display(), e);
+                        eprintln!("Warning: Skipping invalid tool {}: {}", path.display(), e);
+                    }
+                }
+            }
+        }
+        tools
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(DEFAULT_TIMEOUT_SECS))
+    }
+
+    pub fn return_output(&self) -> bool {
+        self.return_output.unwrap_or(true)
+    }
+
+    /// The tool as advertised to the model
+    pub fn agent_tool(&self) -> AgentTool {
+        AgentTool::new(
+            &format!("Tool {}", self.name),
+            &self.description,
+            &self.usage(),
+        )
+    }
+
+    /// How the model calls the tool
+    fn usage(&self) -> String {
+        let parameters = self
+            .parameters
+            .as_ref()
+            .map(|parameters| parameters.to_string())
+            .unwrap_or_else(|| "{}".to_string());
+        format!(
+            "{{ type: 'tool-call', tool: '{}', arguments: Object }}, the arguments follow the JSON schema {}",
+            self.name, parameters
+        )
+    }
+
+    /// Validates the arguments against the parameters and builds the command to run
+    pub fn command(&self, arguments: &Value) -> Result<String, String> {
+        let object_schema = serde_json::json!({ "type": "object" });
+        let schema = self.parameters.as_ref().unwrap_or(&object_schema);
+        // a tool without parameters can be called without arguments
+        let no_arguments = serde_json::json!({});
+        let arguments = if arguments.is_null() {
+            &no_arguments
+        } else {
+            arguments
+        };
+        validate(schema, arguments, "arguments")?;
+        // optional arguments that are not given are left out of the command
+        let mut values = schema
+            .get("properties")
+            .and_then(Value::as_object)
+            .map(|properties| {
+                properties
+                    .keys()
+                    .map(|name| (name.as_str(), String::new()))
+                    .collect::<HashMap<_, _>>()
+            })
+            .unwrap_or_default();
+        if let Some(arguments) = arguments.as_object() {
+            for (name, value) in arguments {
+                if let Some(quoted) = values.get_mut(name.as_str()) {
+                    *quoted = shell_quote(value);
+                }
+            }
+        }
+        Ok(PARAMETER
+            .replace_all(&self.command, |captures: &Captures| {
+                values
+                    .get(&captures[1])
+                    .cloned()
+                    .unwrap_or_else(|| captures[0].to_string())
+            })
+            .to_string())
+    }
+}
+
+/// Checks the value against the subset of JSON schema used for tool parameters:
+/// `type`, `enum`, `properties`, `required`, `additionalProperties` and `items`
+fn validate(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    if let Some(kind) = schema.get("type").and_then(Value::as_str) {
+        let matches = match kind {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            _ => true,
+        };
+        if !matches {
+            return Err(format!(
+                "`{}` has to be of type {}, got {}",
+                path, kind, value
+            ));
+        }
+    }
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array)
+        && !allowed.contains(value)
+    {
+        let allowed = allowed.iter().map(Value::to_string).collect::<Vec<_>>();
+        return Err(format!(
+            "`{}` has to be one of {}, got {}",
+            path,
+            allowed.join(", "),
+            value
+        ));
+    }
+    if let Some(object) = value.as_object() {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(name) {
+                    return Err(format!("`{}.{}` is required", path, name));
+                }
+            }
+        }
+        for (name, value) in object {
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(property) => validate(property, value, &format!("{}.{}", path, name))?,
+                None if schema.get("additionalProperties") == Some(&Value::Bool(true)) => {}
+                None => return Err(format!("`{}.{}` is not a known parameter", path, name)),
+            }
+        }
+    }
+    if let Some(items) = value.as_array()
+        && let Some(item_schema) = schema.get("items")
+    {
+        for (index, item) in items.iter().enumerate() {
+            validate(item_schema, item, &format!("{}[{}]", path, index))?;
+        }
+    }
+    Ok(())
+}
+
+/// Quotes the value as a single shell word, or one word per item for an array
+fn shell_quote(value: &Value) -> String {
+    match value {
+        Value::String(text) => format!("'{}'", text.replace('\'', r"'\''")),
+        Value::Array(items) => items.iter().map(shell_quote).collect::<Vec<_>>().join(" "),
+        value => format!("'{}'", value.to_string().replace('\'', r"'\''")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn tool(command: &str) -> PluginTool {
+        PluginTool::parse(&format!(
+            r#"
+            name = "grep"
+            description = "Searches"
+            command = "{}"
+            [parameters]
+            type = "object"
+            properties.pattern = {{ type = "string" }}
+            properties.paths = {{ type = "array", items = {{ type = "string" }} }}
+            required = ["pattern"]
+            "#,
+            command
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn quotes_the_arguments() {
+        let tool = tool("grep -r {{pattern}} {{ paths }}");
+        let command = tool
+            .command(&json!({ "pattern": "it's; rm -rf /", "paths": ["src", "$(id)"] }))
+            .unwrap();
+        assert_eq!(command, r"grep -r 'it'\''s; rm -rf /' 'src' '$(id)'");
+    }
+
+    #[test]
+    fn leaves_out_optional_arguments() {
+        let tool = tool("grep -r {{pattern}} {{paths}}");
+        let command = tool.command(&json!({ "pattern": "fn" })).unwrap();
+        assert_eq!(command, "grep -r 'fn' ");
+    }
+
+    #[test]
+    fn only_replaces_the_parameters() {
+        let tool = tool("grep {{pattern}} {{env.HOME}} {{file:/etc/passwd}} {{cwd}}");
+        let command = tool.command(&json!({ "pattern": "fn" })).unwrap();
+        assert_eq!(
+            command,
+            "grep 'fn' {{env.HOME}} {{file:/etc/passwd}} {{cwd}}"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        let tool = tool("grep {{pattern}}");
+        assert!(tool.command(&json!({})).is_err());
+        assert!(tool.command(&json!({ "pattern": 1 })).is_err());
+        assert!(
+            tool.command(&json!({ "pattern": "fn", "flags": "-v" }))
+                .is_err()
+        );
+    }
+}
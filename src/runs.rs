@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use chrono::Utc;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::{AbortHandle, JoinError};
+use tracing::info;
+
+use crate::a2a::A2aAgents;
+use crate::agent::{Agent, RunOutcome};
+use crate::config::Config;
+use crate::events::{Event, EventKind};
+use crate::journal;
+use crate::mcp::McpServers;
+use crate::message::Message;
+
+/// How many finished runs are kept, the ones that finished first are forgotten beyond that
+const MAX_FINISHED_RUNS: usize = 100;
+
+/// The state of a run
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunState {
+    Running,
+    Completed,
+    Failed,
+    Canceled,
+}
+
+impl RunState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RunState::Running => "running",
+            RunState::Completed => "completed",
+            RunState::Failed => "failed",
+            RunState::Canceled => "canceled",
+        }
+    }
+}
+
+/// What happens in a run, sent to the clients following it
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum RunEvent {
+    /// What the agent did, or an agent it delegated to
+    Agent { event: Event },
+    /// The run is over, the last event
+    Finished {
+        state: RunState,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        outcome: Option<RunOutcome>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+/// A task run in the background by an agent of its own
+pub struct Run {
+    persona: Option<String>,
+    task: String,
+    /// The session the file changes of the run are recorded in
+    session: String,
+    state: RunState,
+    created: String,
+    updated: String,
+    /// When the run finished, to forget the oldest finished runs
+    finished_at: Option<Instant>,
+    /// Every event so far, replayed to the clients that start following the run
+    events: Vec<RunEvent>,
+    conversation: Arc<Mutex<Vec<Message>>>,
+    abort: Option<AbortHandle>,
+    subscribers: Vec<UnboundedSender<RunEvent>>,
+}
+
+impl Run {
+    pub fn persona(&self) -> Option<&str> {
+        self.persona.as_deref()
+    }
+
+    pub fn task(&self) -> &str {
+        &self.task
+    }
+
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    pub fn state(&self) -> RunState {
+        self.state
+    }
+
+    /// When the run was started, in RFC 3339
+    pub fn created(&self) -> &str {
+        &self.created
+    }
+
+    /// When the run last had an event, in RFC 3339
+    pub fn updated(&self) -> &str {
+        &self.updated
+    }
+
+    /// The messages of the agents so far, prefixed with the persona of delegated agents
+    pub fn messages(&self) -> Vec<String> {
+        self.events
+            .iter()
+            .filter_map(|event| match event {
+                RunEvent::Agent {
+                    event:
+                        Event {
+                            agent,
+                            kind: EventKind::Message { text },
+                        },
+                } => Some(match agent {
+                    Some(agent) => format!("[{}] {}", agent, text),
+                    None => text.clone(),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The event the run finished with, if it is over
+    pub fn finished(&self) -> Option<&RunEvent> {
+        self.events
+            .last()
+            .filter(|event| matches!(event, RunEvent::Finished { .. }))
+    }
+
+    /// The conversation of the agent with the model so far
+    pub fn conversation(&self) -> Vec<Message> {
+        self.conversation.lock().unwrap().clone()
+    }
+
+    /// Records the event and sends it to the subscribers, forgetting the ones that went away
+    fn push(&mut self, event: RunEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        self.events.push(event);
+        self.updated = Utc::now().to_rfc3339();
+    }
+
+    fn finish(&mut self, state: RunState, outcome: Option<RunOutcome>, error: Option<String>) {
+        self.state = state;
+        self.abort = None;
+        self.finished_at = Some(Instant::now());
+        self.push(RunEvent::Finished {
+            state,
+            outcome,
+            error,
+        });
+        // closes the streams of the subscribers
+        self.subscribers.clear();
+    }
+}
+
+/// The runs of a server, each in a session of its own that can be undone with `undo`
+pub struct Runs {
+    api_key: String,
+    /// The MCP servers and remote agents of the config, used by the agents of the runs
+    mcp: Arc<McpServers>,
+    a2a: Arc<A2aAgents>,
+    runs: Mutex<HashMap<String, Run>>,
+}
+
+impl Runs {
+    /// Starts the MCP servers and connects to the remote agents of the config
+    pub async fn new(api_key: String, config: &Config) -> Arc<Self> {
+        Arc::new(Self {
+            api_key,
+            mcp: Arc::new(McpServers::start(config.mcp_servers()).await),
+            a2a: Arc::new(A2aAgents::connect(config.a2a_agents()).await),
+            runs: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Starts an agent on the task in the background, adopting the persona.
+    /// Without an id, the run is named after its session.
+    pub fn start(
+        self: &Arc<Self>,
+        id: Option<String>,
+        persona: Option<String>,
+        task: String,
+    ) -> Result<String, String> {
+        let session = journal::new_session_id();
+        let id = id.unwrap_or_else(|| session.clone());
+        let (events, mut agent_events) = mpsc::unbounded_channel::<Event>();
+        let mut agent = Agent::new(self.api_key.clone(), &session);
+        agent.subscribe(Arc::new(events));
+        agent.set_interactive(false);
+        agent.set_mcp(self.mcp.clone());
+        agent.set_a2a(self.a2a.clone());
+        agent
+            .set_persona(persona.clone())
+            .map_err(|e| e.to_string())?;
+        let conversation = agent.conversation();
+
+        let mut runs = self.runs.lock().unwrap();
+        if runs.contains_key(&id) {
+            return Err(format!("Run `{}` already exists", id));
+        }
+        info!("[Runs] Starting {} in session {}", id, session);
+        let run_task = task.clone();
+        let run =
+            tokio::spawn(async move { agent.run(&run_task).await.map_err(|e| e.to_string()) });
+        let now = Utc::now().to_rfc3339();
+        runs.insert(
+            id.clone(),
+            Run {
+                persona,
+                task,
+                session,
+                state: RunState::Running,
+                created: now.clone(),
+                updated: now,
+                finished_at: None,
+                events: vec![],
+                conversation,
+                abort: Some(run.abort_handle()),
+                subscribers: vec![],
+            },
+        );
+
+        let runs = self.clone();
+        let run_id = id.clone();
+        tokio::spawn(async move {
+            // the events end once the agent is dropped, when it is done or canceled
+            while let Some(event) = agent_events.recv().await {
+                runs.agent_event(&run_id, event);
+            }
+            runs.finish(&run_id, run.await);
+        });
+        Ok(id)
+    }
+
+    fn agent_event(&self, id: &str, event: Event) {
+        let mut runs = self.runs.lock().unwrap();
+        if let Some(run) = runs.get_mut(id)
+            && run.state == RunState::Running
+        {
+            run.push(RunEvent::Agent { event });
+        }
+    }
+
+    /// Records the result of the run, unless it was canceled
+    fn finish(&self, id: &str, result: Result<Result<RunOutcome, String>, JoinError>) {
+        let mut runs = self.runs.lock().unwrap();
+        let Some(run) = runs.get_mut(id) else {
+            return;
+        };
+        if run.state != RunState::Running {
+            return;
+        }
+        match result {
+            Ok(Ok(outcome)) => {
+                info!("[Runs] {} completed", id);
+                run.finish(RunState::Completed, Some(outcome), None);
+            }
+            Ok(Err(e)) => {
+                info!("[Runs] {} failed: {}", id, e);
+                run.finish(RunState::Failed, None, Some(e));
+            }
+            Err(e) if e.is_panic() => {
+                let panic = e.into_panic();
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                info!("[Runs] {} panicked: {}", id, message);
+                run.finish(
+                    RunState::Failed,
+                    None,
+                    Some(format!("The agent crashed: {}", message)),
+                );
+            }
+            Err(e) => {
+                info!("[Runs] {} stopped: {}", id, e);
+                run.finish(RunState::Canceled, None, None);
+            }
+        }
+        forget_oldest_finished(&mut runs);
+    }
+
+    /// Stops the run, returns an error if it is not found or already over
+    pub fn cancel(&self, id: &str) -> Result<(), CancelError> {
+        let mut runs = self.runs.lock().unwrap();
+        let run = runs.get_mut(id).ok_or(CancelError::NotFound)?;
+        let abort = run.abort.take().ok_or(CancelError::Finished(run.state))?;
+        info!("[Runs] Canceling {}", id);
+        // dropping the agent kills the command it is running, see `shell::ProcessGroup`
+        abort.abort();
+        run.finish(RunState::Canceled, None, None);
+        forget_oldest_finished(&mut runs);
+        Ok(())
+    }
+
+    /// Follows the events of the run, starting with the ones so far.
+    /// The stream ends after the run finished.
+    pub fn subscribe(&self, id: &str) -> Option<UnboundedReceiver<RunEvent>> {
+        let mut runs = self.runs.lock().unwrap();
+        let run = runs.get_mut(id)?;
+        let (subscriber, events) = mpsc::unbounded_channel();
+        for event in &run.events {
+            let _ = subscriber.send(event.clone());
+        }
+        if run.state == RunState::Running {
+            run.subscribers.push(subscriber);
+        }
+        Some(events)
+    }
+
+    /// Waits for the run to finish
+    pub async fn wait(&self, id: &str) {
+        if let Some(mut events) = self.subscribe(id) {
+            while events.recv().await.is_some() {}
+        }
+    }
+
+    /// Reads the run
+    pub fn with_run<T>(&self, id: &str, read: impl FnOnce(&Run) -> T) -> Option<T> {
+        self.runs.lock().unwrap().get(id).map(read)
+    }
+
+    /// Reads every run, by id
+    pub fn with_runs<T>(&self, read: impl FnOnce(&HashMap<String, Run>) -> T) -> T {
+        read(&self.runs.lock().unwrap())
+    }
+}
+
+/// Forgets the runs that finished first, keeping at most [`MAX_FINISHED_RUNS`] finished runs
+fn forget_oldest_finished(runs: &mut HashMap<String, Run>) {
+    let mut finished = runs
+        .iter()
+        .filter_map(|(id, run)| Some((run.finished_at?, id.clone())))
+        .collect::<Vec<_>>();
+    if finished.len() <= MAX_FINISHED_RUNS {
+        return;
+    }
+    finished.sort();
+    for (_, id) in &finished[..finished.len() - MAX_FINISHED_RUNS] {
+        info!("[Runs] Forgetting {}", id);
+        runs.remove(id);
+    }
+}
+
+/// Why a run cannot be canceled
+pub enum CancelError {
+    NotFound,
+    Finished(RunState),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runs_with(id: &str) -> Runs {
+        let now = Utc::now().to_rfc3339();
+        let run = Run {
+            persona: None,
+            task: "Fix the tests".to_string(),
+            session: id.to_string(),
+            state: RunState::Running,
+            created: now.clone(),
+            updated: now,
+            finished_at: None,
+            events: vec![],
+            conversation: Arc::new(Mutex::new(vec![])),
+            abort: None,
+            subscribers: vec![],
+        };
+        Runs {
+            api_key: "key".to_string(),
+            mcp: Arc::new(McpServers::default()),
+            a2a: Arc::new(A2aAgents::default()),
+            runs: Mutex::new(HashMap::from([(id.to_string(), run)])),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_a_crashed_run_as_failed() {
+        let runs = runs_with("crashed");
+        let result = tokio::spawn(async { panic!("index out of bounds") }).await;
+        runs.finish("crashed", result);
+        let (state, finished) = runs
+            .with_run("crashed", |run| (run.state(), run.finished().cloned()))
+            .unwrap();
+        assert_eq!(state, RunState::Failed);
+        assert!(matches!(
+            finished,
+            Some(RunEvent::Finished { error: Some(error), .. })
+                if error == "The agent crashed: index out of bounds"
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_an_aborted_run_as_canceled() {
+        let runs = runs_with("aborted");
+        let task = tokio::spawn(std::future::pending::<Result<RunOutcome, String>>());
+        task.abort();
+        runs.finish("aborted", task.await);
+        assert_eq!(
+            runs.with_run("aborted", |run| run.state()).unwrap(),
+            RunState::Canceled
+        );
+    }
+}
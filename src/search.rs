@@ -0,0 +1,151 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use ignore::WalkBuilder;
+use regex::Regex;
+
+use crate::file_read::is_binary;
+
+pub const DEFAULT_MAX_MATCHES: usize = 100;
+
+/// Searches the files under `root` for lines matching `pattern`.
+/// Files ignored by `.gitignore` and hidden files are skipped, as are binary files.
+/// Matches are returned as `path:line: text`, with context lines as `path-line- text`
+/// and `--` between non-contiguous groups, like grep does.
+/// At most `max_matches` matches are returned, which has to be at least 1.
+pub fn search(
+    pattern: &str,
+    root: &Path,
+    context: usize,
+    max_matches: usize,
+) -> Result<String, Box<dyn Error>> {
+    if max_matches == 0 {
+        return Err("max_matches has to be at least 1".into());
+    }
+    let regex = Regex::new(pattern)?;
+    let mut output = Vec::new();
+    let mut matches = 0;
+    let mut truncated = false;
+
+    for entry in WalkBuilder::new(root).build() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        let Ok(bytes) = fs::read(entry.path()) else {
+            continue;
+        };
+        if is_binary(&bytes) {
+            continue;
+        }
+        let content = String::from_utf8_lossy(&bytes);
+        let lines = content.lines().collect::<Vec<_>>();
+        let path = entry.path().display();
+
+        // index of the line after the last one that has been printed for this file
+        let mut printed_until = 0;
+        for (index, line) in lines.iter().enumerate() {
+            if !regex.is_match(line) {
+                continue;
+            }
+            if matches == max_matches {
+                truncated = true;
+                break;
+            }
+            matches += 1;
+            let from = index.saturating_sub(context).max(printed_until);
+            if context > 0 && printed_until > 0 && from > printed_until {
+                output.push("--".to_string());
+            }
+            for (i, line) in lines.iter().enumerate().take(index).skip(from) {
+                output.push(format!("{}-{}- {}", path, i + 1, line));
+            }
+            output.push(format!("{}:{}: {}", path, index + 1, line));
+            // trailing context stops at the next match, which prints itself and its own context
+            let to = (index + context + 1).min(lines.len());
+            let mut end = index + 1;
+            while end < to && !regex.is_match(lines[end]) {
+                output.push(format!("{}-{}- {}", path, end + 1, lines[end]));
+                end += 1;
+            }
+            printed_until = end;
+        }
+        if truncated {
+            break;
+        }
+    }
+
+    if output.is_empty() {
+        return Ok(format!("No matches found for `{}`.", pattern));
+    }
+    if truncated {
+        output.push(format!(
+            "[Output truncated after {} matches. Narrow down the pattern or path to see more.]",
+            max_matches
+        ));
+    }
+    Ok(output.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    /// A new empty directory under the temporary directory
+
+    #[test]
+    fn finds_matches_with_context() {
+        let dir = temp_dir("search_context");
+        fs::write(dir.join("a.txt"), "one\ntwo\nthree\nfour\n").unwrap();
+        let result = search("three", &dir, 1, 10).unwrap();
+        let path = dir.join("a.txt").display().to_string();
+        assert_eq!(
+            result,
+            format!("{0}-2- two\n{0}:3: three\n{0}-4- four", path)
+        );
+    }
+
+    #[test]
+    fn reports_no_matches() {
+        let dir = temp_dir("search_none");
+        fs::write(dir.join("a.txt"), "one\n").unwrap();
+        assert_eq!(
+            search("two", &dir, 0, 10).unwrap(),
+            "No matches found for `two`."
+        );
+    }
+
+    #[test]
+    fn truncates_after_max_matches() {
+        let dir = temp_dir("search_truncated");
+        fs::write(dir.join("a.txt"), "x\nx\nx\n").unwrap();
+        let result = search("x", &dir, 0, 2).unwrap();
+        assert_eq!(
+            result.lines().filter(|line| line.ends_with(": x")).count(),
+            2
+        );
+        assert!(result.contains("[Output truncated after 2 matches."));
+    }
+
+    #[test]
+    fn rejects_zero_max_matches() {
+        let dir = temp_dir("search_zero");
+        fs::write(dir.join("a.txt"), "x\n").unwrap();
+        assert!(search("x", &dir, 0, 0).is_err());
+    }
+
+    #[test]
+    fn skips_binary_files() {
+        let dir = temp_dir("search_binary");
+        fs::write(dir.join("a.bin"), b"x\0x").unwrap();
+        assert_eq!(
+            search("x", &dir, 0, 10).unwrap(),
+            "No matches found for `x`."
+        );
+    }
+}
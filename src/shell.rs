@@ -0,0 +1,109 @@
+use std::io;
+use std::path::Path;
+use std::process::{Output, Stdio};
+use std::time::Duration;
+
+use tokio::process::{Child, Command};
+
+/// A command run with `sh -c` in `dir`. Unless it is `interactive`, reading from the terminal,
+/// it runs in a process group of its own so that the processes it starts can be stopped along
+/// with it, see [`ProcessGroup`].
+pub fn command(command: &str, dir: &Path, interactive: bool) -> Command {
+    let mut shell = Command::new("sh");
+    shell
+        .arg("-c")
+        .arg(command)
+        .current_dir(dir)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    if interactive {
+        // a process group of its own would be stopped when reading from the terminal
+        shell.stdin(Stdio::inherit());
+    } else {
+        shell.stdin(Stdio::null()).process_group(0);
+    }
+    shell
+}
+
+/// Runs the command to completion and collects its output.
+/// Returns `None` if it did not finish within `timeout`, after killing its process group.
+pub async fn output(mut command: Command, timeout: Duration) -> io::Result<Option<Output>> {
+    let child = command.spawn()?;
+    let group = ProcessGroup::of(&child);
+    match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(output) => {
+            group.release();
+            output.map(Some)
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Kills the process group of a command started by [`command`] when dropped, unless it is
+/// released once the command finished, so that a command whose run is cancelled or times out
+/// does not leave the processes it started behind.
+pub struct ProcessGroup {
+    pid: Option<u32>,
+}
+
+impl ProcessGroup {
+    pub fn of(child: &Child) -> Self {
+        Self { pid: child.id() }
+    }
+
+    /// Leaves the processes started by the finished command running, e.g. a server started with `&`
+    pub fn release(mut self) {
+        self.pid = None;
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        if let Some(pid) = self.pid {
+            // SAFETY: killpg has no memory safety requirements, it fails if there is no such group,
+            // e.g. for an interactive command that runs in the group of fash
+            unsafe {
+                libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[tokio::test]
+    async fn collects_the_output() {
+        let output = output(
+            command("echo out; echo err >&2", Path::new("."), false),
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "out\n");
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "err\n");
+    }
+
+    #[tokio::test]
+    async fn kills_the_processes_started_by_the_command_on_timeout() {
+        let pid_file = temp_dir("shell").join("pid");
+        let script = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+        let started = std::time::Instant::now();
+        let output = output(command(&script, Path::new("."), false), Duration::from_millis(500))
+            .await
+            .unwrap();
+        assert!(output.is_none());
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // the killed process is gone, or a zombie if nothing reaps it
+        let state =
+            std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())).unwrap_or_default();
+        assert!(state.is_empty() || state.contains(") Z "), "{}", state);
+    }
+}
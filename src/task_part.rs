@@ -1,5 +1,7 @@
-use serde::Deserialize;
-#[derive(Debug, Deserialize)]
+use serde::{Deserialize, Serialize};
+
+/// A part of the response of the model
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "kebab-case")]
 pub enum TaskPart {
     Run {
@@ -24,6 +26,40 @@ pub enum TaskPart {
     },
     FileRead {
         path: String,
+        start: Option<u32>,
+        end: Option<u32>,
+        max_bytes: Option<u32>,
+    },
+    Search {
+        pattern: String,
+        path: Option<String>,
+        context: Option<u32>,
+        max_matches: Option<u32>,
+    },
+    Delegate {
+        persona: String,
+        task: String,
+        /// How many steps the delegated task can take
+        max_steps: Option<u32>,
+    },
+    ToolCall {
+        tool: String,
+        #[serde(default)]
+        arguments: serde_json::Value,
+    },
+    McpResource {
+        server: String,
+        uri: String,
+    },
+    McpPrompt {
+        server: String,
+        prompt: String,
+        #[serde(default)]
+        arguments: serde_json::Value,
+    },
+    RemoteAgent {
+        agent: String,
+        task: String,
     },
     End {
         reason: String,
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+use std::sync::LazyLock;
+
+use chrono::Local;
+use regex::{Captures, Regex};
+use tracing::warn;
+
+static PLACEHOLDER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{\{\s*([^{}]+?)\s*\}\}").unwrap());
+
+/// Replaces the `{{...}}` placeholders in `text`, resolved in the workspace `dir`,
+/// or the current directory without one:
+/// - `{{cwd}}`: the workspace
+/// - `{{date}}`: today's date
+/// - `{{os}}`: the operating system
+/// - `{{git_branch}}`: the current git branch of the workspace, empty outside of a git repository
+/// - `{{env.X}}`: the environment variable `X`, empty if it is not set
+/// - `{{file:path}}`: the content of the file at `path`, relative to the workspace,
+///   empty if it cannot be read
+/// - `{{key}}`: the value of the user supplied variable `key`, these take precedence over the above
+///
+/// Unknown placeholders are left as they are.
+pub fn render(text: &str, vars: &HashMap<String, String>, dir: Option<&Path>) -> String {
+    PLACEHOLDER
+        .replace_all(text, |captures: &Captures| {
+            let key = &captures[1];
+            resolve(key, vars, dir).unwrap_or_else(|| {
+                warn!("Unknown placeholder {}", &captures[0]);
+                captures[0].to_string()
+            })
+        })
+        .to_string()
+}
+
+fn resolve(key: &str, vars: &HashMap<String, String>, dir: Option<&Path>) -> Option<String> {
+    if let Some(value) = vars.get(key) {
+        return Some(value.clone());
+    }
+    if let Some(name) = key.strip_prefix("env.") {
+        return Some(std::env::var(name).unwrap_or_default());
+    }
+    if let Some(path) = key.strip_prefix("file:") {
+        let file = match dir {
+            Some(dir) => dir.join(path.trim()),
+            None => path.trim().into(),
+        };
+        return Some(std::fs::read_to_string(file).unwrap_or_else(|e| {
+            warn!("Failed to read {} for a placeholder: {}", path, e);
+            String::new()
+        }));
+    }
+    match key {
+        "cwd" => match dir {
+            Some(dir) => Some(dir.display().to_string()),
+            None => std::env::current_dir()
+                .ok()
+                .map(|cwd| cwd.display().to_string()),
+        },
+        "date" => Some(Local::now().format("%Y-%m-%d").to_string()),
+        "os" => Some(std::env::consts::OS.to_string()),
+        "git_branch" => Some(git_branch(dir).unwrap_or_default()),
+        _ => None,
+    }
+}
+
+fn git_branch(dir: Option<&Path>) -> Option<String> {
+    let mut command = Command::new("git");
+    if let Some(dir) = dir {
+        command.current_dir(dir);
+    }
+    let output = command
+        .args(["rev-parse", "--abbrev-ref", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Parses a `key=value` variable given on the command line
+pub fn parse_var(var: &str) -> Result<(String, String), String> {
+    let (key, value) = var
+        .split_once('=')
+        .ok_or_else(|| format!("expected `key=value`, got `{}`", var))?;
+    Ok((key.trim().to_string(), value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn vars(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn renders_user_variables() {
+        assert_eq!(
+            render(
+                "Review {{ module }} for {{team}}.",
+                &vars(&[("module", "auth"), ("team", "core")]),
+                None
+            ),
+            "Review auth for core."
+        );
+    }
+
+    #[test]
+    fn user_variables_take_precedence() {
+        assert_eq!(render("{{os}}", &vars(&[("os", "plan9")]), None), "plan9");
+        assert_eq!(
+            render("{{os}}", &HashMap::new(), None),
+            std::env::consts::OS
+        );
+    }
+
+    #[test]
+    fn renders_environment_variables() {
+        assert_eq!(
+            render("{{env.PATH}}", &HashMap::new(), None),
+            std::env::var("PATH").unwrap_or_default()
+        );
+        assert_eq!(
+            render("[{{env.FASH_TEST_UNSET_VAR}}]", &HashMap::new(), None),
+            "[]"
+        );
+    }
+
+    #[test]
+    fn renders_files() {
+        let path = temp_dir("template_files").join("rules.md");
+        std::fs::write(&path, "Use tabs.").unwrap();
+        assert_eq!(
+            render(
+                &format!("Rules: {{{{file:{}}}}}", path.display()),
+                &HashMap::new(),
+                None
+            ),
+            "Rules: Use tabs."
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            render(
+                &format!("Rules: {{{{file:{}}}}}", path.display()),
+                &HashMap::new(),
+                None
+            ),
+            "Rules: "
+        );
+    }
+
+    #[test]
+    fn renders_in_the_workspace() {
+        let dir = temp_dir("template_workspace");
+        std::fs::write(dir.join("rules.md"), "Use tabs.").unwrap();
+        assert_eq!(
+            render("{{cwd}}: {{file:rules.md}}", &HashMap::new(), Some(&dir)),
+            format!("{}: Use tabs.", dir.display())
+        );
+        // the tests run in the repository of the crate, the workspace is outside of it
+        assert_eq!(
+            render("[{{git_branch}}]", &HashMap::new(), Some(&dir)),
+            "[]"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn leaves_unknown_placeholders() {
+        assert_eq!(
+            render("{{unknown}} and {{ }}", &HashMap::new(), None),
+            "{{unknown}} and {{ }}"
+        );
+    }
+
+    #[test]
+    fn parses_variables() {
+        assert_eq!(
+            parse_var("query= a=b ").unwrap(),
+            ("query".to_string(), " a=b ".to_string())
+        );
+        assert!(parse_var("query").is_err());
+    }
+}
@@ -0,0 +1,11 @@
+//! Helpers shared by the tests of the modules
+
+use std::path::PathBuf;
+
+/// An empty directory for a test, `fash_<name>_<pid>` in the temporary directory
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fash_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}